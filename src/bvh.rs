@@ -0,0 +1,292 @@
+use crate::{
+    hit::Hit,
+    ray::Ray,
+    utils::{get_max, get_min, prior_hit},
+};
+use core::f64;
+use vecmat::vector::Vector3;
+
+const MAX_LEAF_SIZE: usize = 4;
+const BUCKET_NUMBER: usize = 12;
+// SAH里遍历一次节点相对于求交一次的代价
+const TRAVERSAL_COST: f64 = 0.125;
+
+#[derive(Clone, Copy)]
+pub struct BoundingBox {
+    pub min_pos: Vector3<f64>,
+    pub max_pos: Vector3<f64>,
+}
+
+impl BoundingBox {
+    pub fn new(min_pos: Vector3<f64>, max_pos: Vector3<f64>) -> Self {
+        Self { min_pos, max_pos }
+    }
+    // 平面这种无限大的物体
+    pub fn infinite() -> Self {
+        Self {
+            min_pos: Vector3::<f64>::fill(f64::NEG_INFINITY),
+            max_pos: Vector3::<f64>::fill(f64::INFINITY),
+        }
+    }
+    // 空盒子，和任何盒子求并都得到那个盒子
+    pub fn empty() -> Self {
+        Self {
+            min_pos: Vector3::<f64>::fill(f64::INFINITY),
+            max_pos: Vector3::<f64>::fill(f64::NEG_INFINITY),
+        }
+    }
+    pub fn from_points(points: &[Vector3<f64>]) -> Self {
+        points.iter().fold(Self::empty(), |bbox, p| {
+            Self::new(get_min(&bbox.min_pos, p), get_max(&bbox.max_pos, p))
+        })
+    }
+    pub fn is_finite(&self) -> bool {
+        self.min_pos
+            .iter()
+            .chain(self.max_pos.iter())
+            .all(|x| x.is_finite())
+    }
+    pub fn union(&self, other: &BoundingBox) -> Self {
+        Self::new(
+            get_min(&self.min_pos, &other.min_pos),
+            get_max(&self.max_pos, &other.max_pos),
+        )
+    }
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max_pos - self.min_pos;
+        if d.x() < 0. || d.y() < 0. || d.z() < 0. {
+            0.
+        } else {
+            2. * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+        }
+    }
+    pub fn centroid(&self) -> Vector3<f64> {
+        (self.min_pos + self.max_pos) / 2.
+    }
+    pub fn corners(&self) -> [Vector3<f64>; 8] {
+        let (a, b) = (self.min_pos, self.max_pos);
+        [
+            Vector3::<f64>::from([a.x(), a.y(), a.z()]),
+            Vector3::<f64>::from([a.x(), a.y(), b.z()]),
+            Vector3::<f64>::from([a.x(), b.y(), a.z()]),
+            Vector3::<f64>::from([a.x(), b.y(), b.z()]),
+            Vector3::<f64>::from([b.x(), a.y(), a.z()]),
+            Vector3::<f64>::from([b.x(), a.y(), b.z()]),
+            Vector3::<f64>::from([b.x(), b.y(), a.z()]),
+            Vector3::<f64>::from([b.x(), b.y(), b.z()]),
+        ]
+    }
+    // slab法，返回光线在盒子里的参数区间
+    pub fn intersect(&self, ray: &Ray, tmin: f64, tmax: f64) -> Option<(f64, f64)> {
+        let o = ray.get_origin();
+        let d = ray.get_direction();
+        let mut t_near = tmin;
+        let mut t_far = tmax;
+        for i in 0..3_usize {
+            if d[i] == 0. {
+                if o[i] < self.min_pos[i] || o[i] > self.max_pos[i] {
+                    return None;
+                }
+            } else {
+                let t1 = (self.min_pos[i] - o[i]) / d[i];
+                let t2 = (self.max_pos[i] - o[i]) / d[i];
+                t_near = t_near.max(t1.min(t2));
+                t_far = t_far.min(t1.max(t2));
+            }
+        }
+        if t_near > t_far {
+            None
+        } else {
+            Some((t_near, t_far))
+        }
+    }
+}
+
+struct BvhNode {
+    bbox: BoundingBox,
+    // 叶子是[first, first + count)这段下标，内部节点的count为0，左孩子紧跟在后面，first是右孩子
+    first: usize,
+    count: usize,
+    axis: usize, //内部节点的划分维度，遍历时用来决定先走哪边
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    bbox: BoundingBox,
+    count: usize,
+}
+
+// 只管盒子和下标，求交交给调用者的闭包
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(boxes: &[BoundingBox]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            indices: (0..boxes.len()).collect(),
+        };
+        if !boxes.is_empty() {
+            let centroids: Vec<Vector3<f64>> = boxes.iter().map(|b| b.centroid()).collect();
+            let len = boxes.len();
+            bvh.build(boxes, &centroids, 0, len);
+        }
+        bvh
+    }
+    fn build(
+        &mut self,
+        boxes: &[BoundingBox],
+        centroids: &[Vector3<f64>],
+        left: usize,
+        right: usize,
+    ) -> usize {
+        let node_index = self.nodes.len();
+        let bbox = self.indices[left..right]
+            .iter()
+            .fold(BoundingBox::empty(), |bbox, &i| bbox.union(&boxes[i]));
+        self.nodes.push(BvhNode {
+            bbox,
+            first: left,
+            count: right - left,
+            axis: 0,
+        });
+        let count = right - left;
+        if count == 1 {
+            return node_index;
+        }
+        let centroid_box = BoundingBox::from_points(
+            &self.indices[left..right]
+                .iter()
+                .map(|&i| centroids[i])
+                .collect::<Vec<_>>(),
+        );
+        let extent = centroid_box.max_pos - centroid_box.min_pos;
+        let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() {
+            0
+        } else if extent.y() >= extent.z() {
+            1
+        } else {
+            2
+        };
+        // None表示SAH分不开，退回按中位数分
+        let split = if extent[axis] <= 0. {
+            // 中心点全重合
+            if count <= MAX_LEAF_SIZE {
+                return node_index;
+            }
+            None
+        } else {
+            // 按中心点分桶，枚举桶之间的分界算SAH代价
+            let lower = centroid_box.min_pos[axis];
+            let bucket_of = |i: usize| {
+                let b =
+                    ((centroids[i][axis] - lower) / extent[axis] * BUCKET_NUMBER as f64) as usize;
+                b.min(BUCKET_NUMBER - 1)
+            };
+            let mut buckets = [Bucket {
+                bbox: BoundingBox::empty(),
+                count: 0,
+            }; BUCKET_NUMBER];
+            for &i in &self.indices[left..right] {
+                let bucket = &mut buckets[bucket_of(i)];
+                bucket.bbox = bucket.bbox.union(&boxes[i]);
+                bucket.count += 1;
+            }
+            let mut suffix = vec![(BoundingBox::empty(), 0_usize); BUCKET_NUMBER];
+            let mut acc = (BoundingBox::empty(), 0_usize);
+            for k in (1..BUCKET_NUMBER).rev() {
+                acc = (acc.0.union(&buckets[k].bbox), acc.1 + buckets[k].count);
+                suffix[k] = acc;
+            }
+            let area = bbox.surface_area();
+            let mut best = (f64::INFINITY, 0_usize);
+            let mut prefix = (BoundingBox::empty(), 0_usize);
+            for k in 0..BUCKET_NUMBER - 1 {
+                prefix = (
+                    prefix.0.union(&buckets[k].bbox),
+                    prefix.1 + buckets[k].count,
+                );
+                let (right_box, right_count) = suffix[k + 1];
+                if prefix.1 == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST
+                    + (prefix.0.surface_area() * prefix.1 as f64
+                        + right_box.surface_area() * right_count as f64)
+                        / area;
+                if cost < best.0 {
+                    best = (cost, k);
+                }
+            }
+            if count <= MAX_LEAF_SIZE && best.0 >= count as f64 {
+                return node_index;
+            }
+            // 把分界左边的桶挪到前面
+            let mut mid = left;
+            for j in left..right {
+                if bucket_of(self.indices[j]) <= best.1 {
+                    self.indices.swap(mid, j);
+                    mid += 1;
+                }
+            }
+            if mid == left || mid == right {
+                None
+            } else {
+                Some(mid)
+            }
+        };
+        let mid = split.unwrap_or_else(|| {
+            let mid = (left + right) / 2;
+            self.indices[left..right].select_nth_unstable_by(mid - left, |&a, &b| {
+                centroids[a][axis].partial_cmp(&centroids[b][axis]).unwrap()
+            });
+            mid
+        });
+        self.build(boxes, centroids, left, mid);
+        let right_child = self.build(boxes, centroids, mid, right);
+        let node = &mut self.nodes[node_index];
+        node.first = right_child;
+        node.count = 0;
+        node.axis = axis;
+        node_index
+    }
+    pub fn bounding_box(&self) -> BoundingBox {
+        self.nodes
+            .first()
+            .map(|node| node.bbox)
+            .unwrap_or_else(BoundingBox::empty)
+    }
+    // 从近到远遍历和光线相交的叶子，已经找到的最近交点会剪掉更远的盒子
+    pub fn intersect<F>(&self, ray: &Ray, tmin: f64, mut f: F) -> Option<Hit>
+    where
+        F: FnMut(usize) -> Option<Hit>,
+    {
+        let mut ret: Option<Hit> = None;
+        if self.nodes.is_empty() {
+            return ret;
+        }
+        let mut stack = vec![0_usize];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            let tmax = ret.as_ref().map_or(f64::INFINITY, |h| h.get_t());
+            if node.bbox.intersect(ray, tmin, tmax).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for &i in &self.indices[node.first..node.first + node.count] {
+                    ret = prior_hit(ret, f(i));
+                }
+            } else if ray.get_direction()[node.axis] > 0. {
+                // 栈是后进先出，远的孩子先压栈
+                stack.push(node.first);
+                stack.push(node_index + 1);
+            } else {
+                stack.push(node_index + 1);
+                stack.push(node.first);
+            }
+        }
+        ret
+    }
+}
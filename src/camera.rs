@@ -6,7 +6,7 @@ use vecmat::{matrix::Matrix3x3, traits::Dot, vector::Vector3, Matrix, Vector};
 
 use crate::object3d::{Object3d, Plane};
 use crate::utils::parse_vector;
-use crate::{materials::DiffuseMaterial, ray::Ray};
+use crate::{materials::DiffuseMaterial, ray::Ray, texture::ConstantTexture};
 
 pub struct PerspectiveCamera {
     center: Vector3<f64>,
@@ -133,7 +133,9 @@ impl Camera for DoFCamera {
                 .transpose();
         let dir = rot.dot(dir).normalize();
         let temp_ray = Ray::new(self.center, dir, None);
-        let temp_material = Arc::new(DiffuseMaterial::new(Vector3::<f64>::from([1., 1., 1.])));
+        let temp_material = Arc::new(DiffuseMaterial::new(Arc::new(ConstantTexture::new(
+            Vector3::<f64>::from([1., 1., 1.]),
+        ))));
         let focus_plane = Plane::new(temp_material, self.direction, self.focus_dist);
         let hit = focus_plane.intersect(&temp_ray, 0.015).unwrap(); //这里保证有交
         let delta: Vector3<f64> = self.aperture * (normal_x * self.horizontal + normal_y * self.up);
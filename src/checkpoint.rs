@@ -0,0 +1,118 @@
+use crate::{photon::HitPoint, settings::RenderSettings};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Write},
+};
+use vecmat::vector::Vector3;
+
+const MAGIC: &[u8; 8] = b"SPPMCKPT";
+const VERSION: u32 = 2;
+
+// 渐进式渲染的中间状态，够接着往下渲染就行了
+// 文件格式：魔数、版本、已完成轮数、每个光源已发出的光子数、宽、高、设置（json文本），
+// 然后逐像素存radius、n、tau、direct，全部是小端序
+pub struct Checkpoint {
+    pub round: usize,
+    pub emitted: f64,
+    pub width: usize,
+    pub height: usize,
+    pub settings: RenderSettings,
+    pub picture: Vec<HitPoint>,
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f64<W: Write>(writer: &mut W, value: f64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0_u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    let mut bytes = [0_u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Checkpoint {
+    // 先写到临时文件再改名，写到一半崩溃也不会把上一个检查点弄坏
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        write_u64(&mut writer, self.round as u64)?;
+        write_f64(&mut writer, self.emitted)?;
+        write_u64(&mut writer, self.width as u64)?;
+        write_u64(&mut writer, self.height as u64)?;
+        let settings = self.settings.to_json().dump();
+        write_u64(&mut writer, settings.len() as u64)?;
+        writer.write_all(settings.as_bytes())?;
+        for pixel in &self.picture {
+            write_f64(&mut writer, pixel.radius)?;
+            write_f64(&mut writer, pixel.n)?;
+            for value in pixel.tau.iter().chain(pixel.direct.iter()) {
+                write_f64(&mut writer, *value)?;
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0_u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a checkpoint file."));
+        }
+        let mut version = [0_u8; 4];
+        reader.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != VERSION {
+            return Err(invalid("Unsupported checkpoint version."));
+        }
+        let round = read_u64(&mut reader)? as usize;
+        let emitted = read_f64(&mut reader)?;
+        let width = read_u64(&mut reader)? as usize;
+        let height = read_u64(&mut reader)? as usize;
+        let mut settings = vec![0_u8; read_u64(&mut reader)? as usize];
+        reader.read_exact(&mut settings)?;
+        let settings = String::from_utf8(settings).map_err(|_| invalid("Settings invalid!"))?;
+        let settings = json::parse(&settings).map_err(|_| invalid("Settings invalid!"))?;
+        let mut render_settings = RenderSettings::new();
+        render_settings.update(&settings);
+        let mut picture = Vec::with_capacity(width * height);
+        for _ in 0..width * height {
+            let mut pixel = HitPoint::new(read_f64(&mut reader)?);
+            pixel.n = read_f64(&mut reader)?;
+            pixel.tau = Vector3::<f64>::from([
+                read_f64(&mut reader)?,
+                read_f64(&mut reader)?,
+                read_f64(&mut reader)?,
+            ]);
+            pixel.direct = Vector3::<f64>::from([
+                read_f64(&mut reader)?,
+                read_f64(&mut reader)?,
+                read_f64(&mut reader)?,
+            ]);
+            picture.push(pixel);
+        }
+        Ok(Self {
+            round,
+            emitted,
+            width,
+            height,
+            settings: render_settings,
+            picture,
+        })
+    }
+}
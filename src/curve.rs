@@ -0,0 +1,152 @@
+use crate::utils::parse_vector2;
+use json::JsonValue;
+use std::sync::Arc;
+use vecmat::vector::Vector2;
+
+// 曲线都定义在xy平面上，x是到旋转轴的距离，y是高度，参数t的范围是[0, 1]
+pub trait Curve {
+    // 返回参数t处的点和切向量（对t求导）
+    fn evaluate(&self, t: f64) -> (Vector2<f64>, Vector2<f64>);
+    fn get_controls(&self) -> &[Vector2<f64>];
+}
+
+pub struct BezierCurve {
+    controls: Vec<Vector2<f64>>,
+}
+
+impl BezierCurve {
+    pub fn new(controls: Vec<Vector2<f64>>) -> Self {
+        assert!(
+            controls.len() >= 2,
+            "Bezier curve needs at least 2 controls!"
+        );
+        Self { controls }
+    }
+}
+
+impl Curve for BezierCurve {
+    fn evaluate(&self, t: f64) -> (Vector2<f64>, Vector2<f64>) {
+        // de Casteljau，倒数第二层的两个点之差就是切向
+        let n = self.controls.len() - 1;
+        let mut points = self.controls.clone();
+        for level in 1..n {
+            for i in 0..=n - level {
+                points[i] = points[i] * (1. - t) + points[i + 1] * t;
+            }
+        }
+        let point = points[0] * (1. - t) + points[1] * t;
+        let tangent = (points[1] - points[0]) * n as f64;
+        (point, tangent)
+    }
+    fn get_controls(&self) -> &[Vector2<f64>] {
+        &self.controls
+    }
+}
+
+pub struct BSplineCurve {
+    controls: Vec<Vector2<f64>>,
+    knots: Vec<f64>,
+    degree: usize,
+}
+
+impl BSplineCurve {
+    pub fn new(controls: Vec<Vector2<f64>>, degree: Option<usize>) -> Self {
+        let degree = degree.unwrap_or(3);
+        assert!(
+            degree >= 1 && controls.len() > degree,
+            "B-spline curve needs more than degree controls!"
+        );
+        // clamped均匀节点，曲线会经过首尾两个控制点
+        let n = controls.len();
+        let inner = n - degree;
+        let mut knots = vec![0.; degree + 1];
+        for i in 1..inner {
+            knots.push(i as f64 / inner as f64);
+        }
+        knots.extend(vec![1.; degree + 1]);
+        Self {
+            controls,
+            knots,
+            degree,
+        }
+    }
+    fn find_span(&self, t: f64) -> usize {
+        let n = self.controls.len();
+        if t >= self.knots[n] {
+            return n - 1;
+        }
+        let mut span = self.degree;
+        while span + 1 < n && self.knots[span + 1] <= t {
+            span += 1;
+        }
+        span
+    }
+    // 在span上用de Boor算法求值，degree和控制点都可以换成求导之后的
+    fn de_boor(
+        knots: &[f64],
+        controls: &[Vector2<f64>],
+        degree: usize,
+        span: usize,
+        t: f64,
+    ) -> Vector2<f64> {
+        let mut points: Vec<Vector2<f64>> =
+            (0..=degree).map(|j| controls[j + span - degree]).collect();
+        for r in 1..=degree {
+            for j in (r..=degree).rev() {
+                let i = j + span - degree;
+                let denom = knots[i + degree + 1 - r] - knots[i];
+                let alpha = if denom == 0. {
+                    0.
+                } else {
+                    (t - knots[i]) / denom
+                };
+                points[j] = points[j - 1] * (1. - alpha) + points[j] * alpha;
+            }
+        }
+        points[degree]
+    }
+}
+
+impl Curve for BSplineCurve {
+    fn evaluate(&self, t: f64) -> (Vector2<f64>, Vector2<f64>) {
+        let t = t.clamp(0., 1.);
+        let span = self.find_span(t);
+        let point = Self::de_boor(&self.knots, &self.controls, self.degree, span, t);
+        // 导数是一条降一阶的B样条，控制点是相邻控制点的差分
+        let p = self.degree as f64;
+        let derived: Vec<Vector2<f64>> = (0..self.controls.len() - 1)
+            .map(|i| {
+                let denom = self.knots[i + self.degree + 1] - self.knots[i + 1];
+                if denom == 0. {
+                    Vector2::<f64>::from([0., 0.])
+                } else {
+                    (self.controls[i + 1] - self.controls[i]) * (p / denom)
+                }
+            })
+            .collect();
+        let tangent = Self::de_boor(
+            &self.knots[1..self.knots.len() - 1],
+            &derived,
+            self.degree - 1,
+            span - 1,
+            t,
+        );
+        (point, tangent)
+    }
+    fn get_controls(&self) -> &[Vector2<f64>] {
+        &self.controls
+    }
+}
+
+pub fn build_curve(curve_attr: &JsonValue) -> Arc<dyn Curve + Send + Sync> {
+    let curve_type = curve_attr["Type"].as_str().unwrap();
+    let controls: Vec<Vector2<f64>> = curve_attr["Controls"]
+        .members()
+        .map(parse_vector2)
+        .collect();
+    match curve_type {
+        "Bezier" => Arc::new(BezierCurve::new(controls)),
+        "BSpline" => Arc::new(BSplineCurve::new(controls, curve_attr["Degree"].as_usize())),
+        _ => panic!("Wrong curve type!"),
+    }
+}
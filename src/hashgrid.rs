@@ -0,0 +1,112 @@
+use crate::photon::{Photon, PhotonMap};
+use vecmat::vector::Vector3;
+
+// 把空间分成边长为cell_size的格子，格子的坐标哈希到一张大小是2的幂的表里
+#[derive(Clone, Copy)]
+pub struct GridHash {
+    cell_size: f64,
+    mask: usize,
+}
+
+impl GridHash {
+    pub fn new(cell_size: f64, entries: usize) -> Self {
+        Self {
+            cell_size: cell_size.max(1e-6),
+            mask: entries.next_power_of_two() - 1,
+        }
+    }
+    pub fn table_size(&self) -> usize {
+        self.mask + 1
+    }
+    pub fn cell(&self, pos: &Vector3<f64>) -> [i64; 3] {
+        [
+            (pos.x() / self.cell_size).floor() as i64,
+            (pos.y() / self.cell_size).floor() as i64,
+            (pos.z() / self.cell_size).floor() as i64,
+        ]
+    }
+    pub fn hash(&self, cell: &[i64; 3]) -> usize {
+        let hash = cell[0].wrapping_mul(73856093)
+            ^ cell[1].wrapping_mul(19349663)
+            ^ cell[2].wrapping_mul(83492791);
+        hash as usize & self.mask
+    }
+    // 和以pos为中心、radius为半径的球相交的所有格子
+    pub fn cells(&self, pos: &Vector3<f64>, radius: f64) -> ([i64; 3], [i64; 3]) {
+        (
+            self.cell(&(*pos - Vector3::<f64>::fill(radius))),
+            self.cell(&(*pos + Vector3::<f64>::fill(radius))),
+        )
+    }
+}
+
+// 按表项做计数排序，返回每个表项的起点和排好的下标，第i个表项是order[start[i]..start[i + 1]]
+pub fn counting_sort(hashes: &[(usize, usize)], table_size: usize) -> (Vec<usize>, Vec<usize>) {
+    let mut start = vec![0; table_size + 1];
+    for &(hash, _) in hashes {
+        start[hash + 1] += 1;
+    }
+    for i in 0..table_size {
+        start[i + 1] += start[i];
+    }
+    let mut next = start.clone();
+    let mut order = vec![0; hashes.len()];
+    for &(hash, index) in hashes {
+        order[next[hash]] = index;
+        next[hash] += 1;
+    }
+    (start, order)
+}
+
+// 光子放在均匀网格里，同一个表项里的光子连续存放
+pub struct HashGrid {
+    grid: GridHash,
+    cell_start: Vec<usize>, //第i个表项的光子是photons[cell_start[i]..cell_start[i + 1]]
+    photons: Vec<Photon>,
+}
+
+impl HashGrid {
+    // cell_size一般取这一轮最大的查询半径，这样每次查询最多看27个格子
+    pub fn new(map: Vec<Photon>, cell_size: f64) -> Self {
+        let grid = GridHash::new(cell_size, map.len());
+        let hashes: Vec<(usize, usize)> = map
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (grid.hash(&grid.cell(&p.pos())), i))
+            .collect();
+        let (cell_start, order) = counting_sort(&hashes, grid.table_size());
+        Self {
+            grid,
+            cell_start,
+            photons: order.iter().map(|&i| map[i]).collect(),
+        }
+    }
+}
+
+impl PhotonMap for HashGrid {
+    fn for_each_candidate(&self, pos: &Vector3<f64>, radius: f64, f: &mut dyn FnMut(&Photon)) {
+        let (lo, hi) = self.grid.cells(pos, radius);
+        let cells = (0..3)
+            .map(|i| (hi[i] - lo[i] + 1) as u64)
+            .fold(1u64, u64::saturating_mul);
+        // 半径比格子大太多时，逐个格子查还不如把所有光子过一遍
+        if cells > self.photons.len() as u64 {
+            self.photons.iter().for_each(f);
+            return;
+        }
+        for x in lo[0]..=hi[0] {
+            for y in lo[1]..=hi[1] {
+                for z in lo[2]..=hi[2] {
+                    let cell = [x, y, z];
+                    let hash = self.grid.hash(&cell);
+                    // 不同的格子可能哈希到同一个表项，只要真正在这个格子里的
+                    for photon in &self.photons[self.cell_start[hash]..self.cell_start[hash + 1]] {
+                        if self.grid.cell(&photon.pos()) == cell {
+                            f(photon);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,20 +1,38 @@
 use crate::materials::Material;
 use std::sync::Arc;
-use vecmat::vector::Vector3;
+use vecmat::{
+    traits::Dot,
+    vector::{Vector2, Vector3},
+};
 
 #[derive(Clone)]
 pub struct Hit {
     t: f64,
     material: Arc<dyn Material>,
     normal: Vector3<f64>,
+    geometric_normal: Vector3<f64>,
+    texcoord: Vector2<f64>,
+    tangent: Vector3<f64>,
+    flipped: bool, //几何法向是否被翻到了光线这一侧，和物体朝外的方向相反
 }
 
 impl Hit {
-    pub fn new(t: f64, material: Arc<dyn Material>, normal: Vector3<f64>) -> Self {
+    // tangent是交点处沿u增大的方向，不要求和normal正交
+    pub fn new(
+        t: f64,
+        material: Arc<dyn Material>,
+        normal: Vector3<f64>,
+        texcoord: Vector2<f64>,
+        tangent: Vector3<f64>,
+    ) -> Self {
         Self {
             t,
             material,
             normal,
+            geometric_normal: normal,
+            texcoord,
+            tangent,
+            flipped: false,
         }
     }
 
@@ -26,7 +44,65 @@ impl Hit {
         &self.material
     }
 
+    // 着色法向，插值、法线贴图之后的结果
     pub fn get_normal(&self) -> &Vector3<f64> {
         &self.normal
     }
+
+    // 几何法向，就是求交时物体给出的法向
+    pub fn get_geometric_normal(&self) -> &Vector3<f64> {
+        &self.geometric_normal
+    }
+
+    // 物体朝外的几何法向，三角形的法向会被翻到光线这一侧，判断光线是射入还是射出要用它
+    pub fn get_outward_normal(&self) -> Vector3<f64> {
+        if self.flipped {
+            -self.geometric_normal
+        } else {
+            self.geometric_normal
+        }
+    }
+
+    pub fn is_flipped(&self) -> bool {
+        self.flipped
+    }
+
+    pub fn set_flipped(&mut self, flipped: bool) {
+        self.flipped = flipped;
+    }
+
+    pub fn get_texcoord(&self) -> &Vector2<f64> {
+        &self.texcoord
+    }
+
+    pub fn get_tangent(&self) -> &Vector3<f64> {
+        &self.tangent
+    }
+
+    // 只改着色法向，几何法向保持不变
+    pub fn set_normal(&mut self, normal: Vector3<f64>) {
+        self.normal = normal;
+    }
+
+    // 扰动后的着色法向如果和几何法向不在光线的同一侧，会把光线引到表面的另一边，
+    // 这时退回几何法向
+    pub fn set_shading_normal(&mut self, normal: Vector3<f64>, direction: &Vector3<f64>) {
+        let shading_side = normal.dot(*direction) < 0.;
+        let geometric_side = self.geometric_normal.dot(*direction) < 0.;
+        self.normal = if shading_side == geometric_side {
+            normal
+        } else {
+            self.geometric_normal
+        };
+    }
+
+    // 按着色法向是反射（或透射）的出射方向，按几何法向看却是透射（或反射），
+    // 就说明光从几何表面漏过去了
+    pub fn leaks(&self, direction_in: &Vector3<f64>, direction_out: &Vector3<f64>) -> bool {
+        let shading_reflect = self.normal.dot(*direction_in) * self.normal.dot(*direction_out) < 0.;
+        let geometric_reflect = self.geometric_normal.dot(*direction_in)
+            * self.geometric_normal.dot(*direction_out)
+            < 0.;
+        shading_reflect != geometric_reflect
+    }
 }
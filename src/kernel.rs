@@ -0,0 +1,63 @@
+use core::f64;
+
+// 密度估计的核函数，t是到收集点的距离除以收集半径，t > 1时权重为0
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kernel {
+    Box,
+    Cone,         //1 - t
+    Epanechnikov, //1 - t^2
+    Silverman,    //(1 - t^2)^2
+}
+
+impl Kernel {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "Box" => Self::Box,
+            "Cone" => Self::Cone,
+            "Epanechnikov" => Self::Epanechnikov,
+            "Silverman" => Self::Silverman,
+            _ => panic!("Unknown kernel {}", name),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Box => "Box",
+            Self::Cone => "Cone",
+            Self::Epanechnikov => "Epanechnikov",
+            Self::Silverman => "Silverman",
+        }
+    }
+    // 距离的平方是dist_sqr时光子的权重，在收集半径外面返回None
+    pub fn weight(&self, dist_sqr: f64, radius: f64) -> Option<f64> {
+        let t_sqr = dist_sqr / (radius * radius);
+        if t_sqr > 1. {
+            return None;
+        }
+        Some(match self {
+            Self::Box => 1.,
+            Self::Cone => 1. - f64::sqrt(t_sqr),
+            Self::Epanechnikov => 1. - t_sqr,
+            Self::Silverman => (1. - t_sqr) * (1. - t_sqr),
+        })
+    }
+    // 权重在半径为radius的圆盘上的积分，表面上的密度估计除以它
+    pub fn area(&self, radius: f64) -> f64 {
+        let factor = match self {
+            Self::Box => 1.,
+            Self::Cone => 1. / 3.,
+            Self::Epanechnikov => 1. / 2.,
+            Self::Silverman => 1. / 3.,
+        };
+        factor * f64::consts::PI * radius * radius
+    }
+    // 权重在半径为radius的球上的积分，介质里的密度估计除以它
+    pub fn volume(&self, radius: f64) -> f64 {
+        let factor = match self {
+            Self::Box => 4. / 3.,
+            Self::Cone => 1. / 3.,
+            Self::Epanechnikov => 8. / 15.,
+            Self::Silverman => 32. / 105.,
+        };
+        factor * f64::consts::PI * radius.powi(3)
+    }
+}
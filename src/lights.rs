@@ -1,29 +1,95 @@
 use crate::{
+    materials::{DiffuseMaterial, EmissiveMaterial, Material},
+    object3d::{Disk, Object3d, Sphere},
     ray::Ray,
-    utils::{gen_vert, parse_vector},
+    spectrum::{tint, to_spectrum, Blackbody},
+    texture::ConstantTexture,
+    utils::{gen_vert, parse_vector, transform_direction, transform_point},
 };
 use core::f64;
 use json::JsonValue;
 use rand::{thread_rng, Rng};
 use std::sync::Arc;
-use vecmat::vector::Vector3;
+use vecmat::{matrix::Matrix4x4, traits::Dot, vector::Vector3};
 
 pub trait Light {
     fn get_ray(&self) -> Ray;
+    // 光谱模式下发出单一波长的光子，RGB的通量上采样成光谱
+    fn get_spectral_ray(&self, wavelength: f64) -> Ray {
+        let mut ray = self.get_ray();
+        ray.set_color(tint(Some(wavelength), ray.get_flux()));
+        ray.set_wavelength(wavelength);
+        ray
+    }
+    // 相机光线能看到的外形，只参与ray_trace，不挡光子
+    fn shape(&self) -> Option<Arc<dyn Object3d + Send + Sync>> {
+        None
+    }
+}
+
+// 外形用不反光的自发光材质，总通量flux均匀分到面积为area的表面上，辐射亮度是flux/(π·area)；
+// 黑体辐射的光源的flux是没染色的，和光子一样按黑体的光谱发光
+fn shape_material(
+    flux: Vector3<f64>,
+    area: f64,
+    blackbody: Option<Blackbody>,
+) -> Arc<dyn Material + Send + Sync> {
+    let black = Arc::new(DiffuseMaterial::new(Arc::new(ConstantTexture::new(
+        Vector3::<f64>::fill(0.),
+    ))));
+    Arc::new(EmissiveMaterial::new(
+        black,
+        flux / (f64::consts::PI * area),
+        None,
+        blackbody,
+    ))
+}
+
+// 圆盘只有正面发光
+fn disk_shape(
+    pos: Vector3<f64>,
+    norm: Vector3<f64>,
+    flux: Vector3<f64>,
+    radius: f64,
+    blackbody: Option<Blackbody>,
+) -> Arc<dyn Object3d + Send + Sync> {
+    let material = shape_material(flux, f64::consts::PI * radius * radius, blackbody);
+    Arc::new(Disk::new(material, pos, norm, radius))
+}
+
+// 黑体辐射的光源在RGB模式下发出的通量，flux按黑体的颜色染色
+fn blackbody_flux(flux: Vector3<f64>, blackbody: Option<Blackbody>) -> Vector3<f64> {
+    blackbody.map_or(flux, |blackbody| flux * blackbody.rgb())
 }
 
 pub struct SphereLight {
     scale: f64,
     pos: Vector3<f64>,
     flux: Vector3<f64>,
+    shape: Option<Arc<dyn Object3d + Send + Sync>>,
 }
 
 impl SphereLight {
-    pub fn new(scale: Option<f64>, pos: Vector3<f64>, flux: Vector3<f64>) -> Self {
+    // 给了半径就是看得见的球
+    pub fn new(
+        scale: Option<f64>,
+        pos: Vector3<f64>,
+        flux: Vector3<f64>,
+        radius: Option<f64>,
+        blackbody: Option<Blackbody>,
+    ) -> Self {
+        let scale = scale.unwrap_or(1.);
+        let shape = radius.map(|radius| -> Arc<dyn Object3d + Send + Sync> {
+            let area = 4. * f64::consts::PI * radius * radius;
+            let material = shape_material(flux * scale, area, blackbody);
+            Arc::new(Sphere::new(material, pos, radius))
+        });
+        let flux = blackbody_flux(flux, blackbody);
         Self {
-            scale: scale.unwrap_or(1.),
+            scale,
             pos,
             flux,
+            shape,
         }
     }
 }
@@ -40,6 +106,9 @@ impl Light for SphereLight {
         ]);
         Ray::new(self.pos, direction, Some(self.flux * self.scale))
     }
+    fn shape(&self) -> Option<Arc<dyn Object3d + Send + Sync>> {
+        self.shape.clone()
+    }
 }
 
 pub struct ConeLight {
@@ -50,26 +119,34 @@ pub struct ConeLight {
     x_axis: Vector3<f64>,
     y_axis: Vector3<f64>,
     angle: f64,
+    shape: Option<Arc<dyn Object3d + Send + Sync>>,
 }
 
 impl ConeLight {
+    // 给了半径就是看得见的圆盘
     pub fn new(
         scale: Option<f64>,
         pos: Vector3<f64>,
         norm: Vector3<f64>,
         flux: Vector3<f64>,
         angle: f64,
+        radius: Option<f64>,
+        blackbody: Option<Blackbody>,
     ) -> Self {
+        let scale = scale.unwrap_or(1.);
         let x_axis = gen_vert(&norm);
         let y_axis: Vector3<f64> = x_axis.cross(norm).normalize();
+        let shape = radius.map(|radius| disk_shape(pos, norm, flux * scale, radius, blackbody));
+        let flux = blackbody_flux(flux, blackbody);
         Self {
-            scale: scale.unwrap_or(1.),
+            scale,
             pos,
             norm,
             flux,
             x_axis,
             y_axis,
             angle,
+            shape,
         }
     }
 }
@@ -85,6 +162,9 @@ impl Light for ConeLight {
             + f64::cos(phi) * self.norm;
         Ray::new(self.pos, direction, Some(self.flux * self.scale))
     }
+    fn shape(&self) -> Option<Arc<dyn Object3d + Send + Sync>> {
+        self.shape.clone()
+    }
 }
 
 pub struct DirectionCircleLight {
@@ -95,26 +175,38 @@ pub struct DirectionCircleLight {
     x_axis: Vector3<f64>,
     y_axis: Vector3<f64>,
     radius: f64,
+    shape: Option<Arc<dyn Object3d + Send + Sync>>,
 }
 
 impl DirectionCircleLight {
+    // 平行光本来只有正对着才看得见，外形按朗伯面近似
     pub fn new(
         scale: Option<f64>,
         pos: Vector3<f64>,
         norm: Vector3<f64>,
         flux: Vector3<f64>,
         radius: f64,
+        visible: bool,
+        blackbody: Option<Blackbody>,
     ) -> Self {
+        let scale = scale.unwrap_or(1.);
         let x_axis = gen_vert(&norm);
         let y_axis: Vector3<f64> = x_axis.cross(norm).normalize();
+        let shape = if visible {
+            Some(disk_shape(pos, norm, flux * scale, radius, blackbody))
+        } else {
+            None
+        };
+        let flux = blackbody_flux(flux, blackbody);
         Self {
-            scale: scale.unwrap_or(1.),
+            scale,
             pos,
             norm,
             flux,
             x_axis,
             y_axis,
             radius,
+            shape,
         }
     }
 }
@@ -130,6 +222,190 @@ impl Light for DirectionCircleLight {
             Some(self.flux * self.scale),
         )
     }
+    fn shape(&self) -> Option<Arc<dyn Object3d + Send + Sync>> {
+        self.shape.clone()
+    }
+}
+
+// 黑体辐射的光源，light按黑体的RGB颜色染过色，RGB模式下直接用；
+// 光谱模式下通量换成flux（没染色的）上采样之后乘上黑体的光谱，看得见的外形也是这样
+pub struct BlackbodyLight {
+    light: Arc<dyn Light + Send + Sync>,
+    flux: Vector3<f64>,
+    blackbody: Blackbody,
+}
+
+impl BlackbodyLight {
+    pub fn new(
+        light: Arc<dyn Light + Send + Sync>,
+        flux: Vector3<f64>,
+        blackbody: Blackbody,
+    ) -> Self {
+        Self {
+            light,
+            flux,
+            blackbody,
+        }
+    }
+}
+
+impl Light for BlackbodyLight {
+    fn get_ray(&self) -> Ray {
+        self.light.get_ray()
+    }
+    fn get_spectral_ray(&self, wavelength: f64) -> Ray {
+        let mut ray = self.light.get_ray();
+        let value = to_spectrum(&self.flux, wavelength) * self.blackbody.value(wavelength);
+        ray.set_color(Vector3::<f64>::fill(value));
+        ray.set_wavelength(wavelength);
+        ray
+    }
+    fn shape(&self) -> Option<Arc<dyn Object3d + Send + Sync>> {
+        self.light.shape()
+    }
+}
+
+// 面光源上的一块，坐标都在世界空间里
+#[derive(Clone, Copy)]
+pub enum Emitter {
+    Sphere(Vector3<f64>, f64),                      //球心和半径，只向外发光
+    Triangle([Vector3<f64>; 3]),                    //两面都发光
+    Disk(Vector3<f64>, Vector3<f64>, Vector3<f64>), //圆心和两条互相垂直的半径，变换之后是椭圆，两面都发光
+}
+
+impl Emitter {
+    // 发光的面积，三角形两面都算上
+    fn area(&self) -> f64 {
+        match self {
+            Self::Sphere(_, radius) => 4. * f64::consts::PI * radius * radius,
+            Self::Triangle(v) => {
+                let normal: Vector3<f64> = (v[1] - v[0]).cross(v[2] - v[0]);
+                normal.length()
+            }
+            Self::Disk(_, u, v) => {
+                let normal: Vector3<f64> = u.cross(*v);
+                2. * f64::consts::PI * normal.length()
+            }
+        }
+    }
+    // 在表面上按面积均匀取一点，返回点和朝发光一侧的法向
+    fn sample<R: Rng>(&self, rng: &mut R) -> (Vector3<f64>, Vector3<f64>) {
+        match self {
+            Self::Sphere(center, radius) => {
+                let theta: f64 = rng.gen_range(0.0..1.0) * 2. * f64::consts::PI;
+                let phi = f64::acos(rng.gen_range(0.0..1.0) * 2. - 1.);
+                let normal = Vector3::<f64>::from([
+                    f64::cos(theta) * f64::sin(phi),
+                    f64::sin(theta) * f64::sin(phi),
+                    f64::cos(phi),
+                ]);
+                (*center + normal * *radius, normal)
+            }
+            Self::Triangle(v) => {
+                let r1 = f64::sqrt(rng.gen_range(0.0..1.0));
+                let r2: f64 = rng.gen_range(0.0..1.0);
+                let point = v[0] * (1. - r1) + v[1] * (r1 * (1. - r2)) + v[2] * (r1 * r2);
+                let normal: Vector3<f64> = (v[1] - v[0]).cross(v[2] - v[0]).normalize();
+                if rng.gen_bool(0.5) {
+                    (point, normal)
+                } else {
+                    (point, -normal)
+                }
+            }
+            Self::Disk(center, u, v) => {
+                // 单位圆盘上的均匀分布经过仿射变换还是均匀的
+                let r = f64::sqrt(rng.gen_range(0.0..1.0));
+                let theta: f64 = rng.gen_range(0.0..1.0) * 2. * f64::consts::PI;
+                let point = *center + *u * (r * f64::cos(theta)) + *v * (r * f64::sin(theta));
+                let normal: Vector3<f64> = u.cross(*v).normalize();
+                if rng.gen_bool(0.5) {
+                    (point, normal)
+                } else {
+                    (point, -normal)
+                }
+            }
+        }
+    }
+    // 三角形的顶点和圆盘的半径直接变换；球只支持平移、旋转和等比缩放，半径按体积的缩放比例算
+    fn transform(&self, forward: &Matrix4x4<f64>) -> Self {
+        match self {
+            Self::Sphere(center, radius) => {
+                let axis = |i: usize| {
+                    let mut dir = Vector3::<f64>::fill(0.);
+                    dir[i] = 1.;
+                    transform_direction(forward, &dir)
+                };
+                let det = axis(0).dot(axis(1).cross(axis(2))).abs();
+                Self::Sphere(transform_point(forward, center), radius * det.cbrt())
+            }
+            Self::Triangle(v) => Self::Triangle([
+                transform_point(forward, &v[0]),
+                transform_point(forward, &v[1]),
+                transform_point(forward, &v[2]),
+            ]),
+            Self::Disk(center, u, v) => Self::Disk(
+                transform_point(forward, center),
+                transform_direction(forward, u),
+                transform_direction(forward, v),
+            ),
+        }
+    }
+}
+
+// 发光物体构成的面光源，按面积选一块，在上面均匀取点，方向按余弦分布
+pub struct AreaLight {
+    emission: Vector3<f64>,
+    emitters: Vec<Emitter>,
+    cdf: Vec<f64>, //按面积累加，最后一项是总面积
+}
+
+impl AreaLight {
+    pub fn new(emission: Vector3<f64>, emitters: Vec<Emitter>) -> Self {
+        let cdf = emitters
+            .iter()
+            .scan(0., |acc, emitter| {
+                *acc += emitter.area();
+                Some(*acc)
+            })
+            .collect();
+        Self {
+            emission,
+            emitters,
+            cdf,
+        }
+    }
+    pub fn transform(&self, forward: &Matrix4x4<f64>) -> Self {
+        let emitters = self.emitters.iter().map(|e| e.transform(forward)).collect();
+        Self::new(self.emission, emitters)
+    }
+    fn area(&self) -> f64 {
+        self.cdf.last().copied().unwrap_or(0.)
+    }
+}
+
+impl Light for AreaLight {
+    fn get_ray(&self) -> Ray {
+        let mut rng = thread_rng();
+        let target = rng.gen_range(0.0..1.0) * self.area();
+        let index = self
+            .cdf
+            .partition_point(|&x| x <= target)
+            .min(self.emitters.len() - 1);
+        let (pos, normal) = self.emitters[index].sample(&mut rng);
+        // 余弦加权的半球方向，辐射亮度为L的面发出的总通量是πLA
+        let x_axis = gen_vert(&normal);
+        let y_axis: Vector3<f64> = x_axis.cross(normal).normalize();
+        let theta: f64 = rng.gen_range(0.0..1.0) * 2. * f64::consts::PI;
+        let r2: f64 = rng.gen_range(0.0..1.0);
+        let direction = f64::cos(theta) * f64::sqrt(r2) * x_axis
+            + f64::sin(theta) * f64::sqrt(r2) * y_axis
+            + f64::sqrt(1. - r2) * normal;
+        Ray::new(
+            pos,
+            direction,
+            Some(self.emission * (f64::consts::PI * self.area())),
+        )
+    }
 }
 
 pub fn build_light(light_attr: &JsonValue) -> Arc<dyn Light + Send + Sync> {
@@ -137,16 +413,45 @@ pub fn build_light(light_attr: &JsonValue) -> Arc<dyn Light + Send + Sync> {
     let scale = light_attr["Scale"].as_f64().unwrap();
     let pos = parse_vector(&light_attr["Position"]);
     let flux = parse_vector(&light_attr["Flux"]);
-    match light_type {
-        "SphereLight" => Arc::new(SphereLight::new(Some(scale), pos, flux)),
+    // 写了Temperature就是黑体辐射，Flux当作染色
+    let blackbody = light_attr["Temperature"].as_f64().map(Blackbody::new);
+    // Visible为true时光源有外形，球和圆盘的半径都写在Radius里
+    let visible = light_attr["Visible"].as_bool().unwrap_or(false);
+    let radius = if visible {
+        Some(
+            light_attr["Radius"]
+                .as_f64()
+                .expect("Visible light needs a radius!"),
+        )
+    } else {
+        None
+    };
+    let light: Arc<dyn Light + Send + Sync> = match light_type {
+        "SphereLight" => Arc::new(SphereLight::new(Some(scale), pos, flux, radius, blackbody)),
         "ConeLight" => {
             let normal = parse_vector(&light_attr["Normal"]);
             let angle = light_attr["Angle"].as_f64().unwrap();
-            Arc::new(ConeLight::new(Some(scale), pos, normal, flux, angle))
+            Arc::new(ConeLight::new(
+                Some(scale),
+                pos,
+                normal,
+                flux,
+                angle,
+                radius,
+                blackbody,
+            ))
         }
         "HalfSphereLight" => {
             let normal = parse_vector(&light_attr["Normal"]);
-            Arc::new(ConeLight::new(Some(scale), pos, normal, flux, 90.))
+            Arc::new(ConeLight::new(
+                Some(scale),
+                pos,
+                normal,
+                flux,
+                90.,
+                radius,
+                blackbody,
+            ))
         }
         "DirectionCircleLight" => {
             let normal = parse_vector(&light_attr["Normal"]);
@@ -157,10 +462,16 @@ pub fn build_light(light_attr: &JsonValue) -> Arc<dyn Light + Send + Sync> {
                 normal,
                 flux,
                 radius,
+                visible,
+                blackbody,
             ))
         }
         _ => {
             panic!("Wrong light type!");
         }
+    };
+    match blackbody {
+        Some(blackbody) => Arc::new(BlackbodyLight::new(light, flux * scale, blackbody)),
+        None => light,
     }
 }
@@ -1,206 +1,629 @@
 #![allow(clippy::too_many_arguments)]
+mod bvh;
 mod camera;
+mod checkpoint;
+mod curve;
+mod hashgrid;
 mod hit;
+mod kernel;
 mod lights;
 mod materials;
+mod medium;
 mod mesh;
+mod microfacet;
 mod object3d;
+mod output;
 mod photon;
 mod ray;
+mod revsurface;
 mod scene_parser;
+mod settings;
+mod spectrum;
+mod sppm;
+mod texture;
+mod tile;
+mod tonemap;
 mod utils;
 use crate::{
+    checkpoint::Checkpoint,
+    lights::Light,
     materials::MaterialType,
+    medium::{cross_surface, enclosing_medium, Interaction, Medium, VolumeMap, VolumePoint},
     object3d::{Group, Object3d},
-    photon::{HitPoint, KDTree, Photon},
+    output::save_image,
+    photon::{build_photon_map, HitPoint, Photon, PhotonMap, VisiblePoint},
     ray::Ray,
-    scene_parser::build_scene_parser,
-    utils::trunc,
+    scene_parser::{build_scene_parser, SceneParser},
+    settings::{parse_args, RenderSettings},
+    spectrum::{sample_wavelength, to_pixel, xyz_to_rgb},
+    sppm::{PixelPoints, VisiblePoints},
+    tile::TileQueue,
 };
 use core::f64;
-use image::{ImageBuffer, ImageError, ImageResult, Rgb};
+use image::{ImageError, ImageResult};
 use std::{
     env,
-    sync::{Arc, Barrier, Mutex},
-    thread, u32, usize,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use vecmat::{
+    traits::Dot,
+    vector::{Vector2, Vector3},
 };
-use vecmat::vector::Vector2;
-
-const PHOTON_NUMBER: u32 = 1000000;
-const ROUND_NUMBER: u32 = 5;
-const SAMPLE_NUMBER: u32 = 8;
-const PARALLEL_NUMBER: usize = 8;
-const T_MIN: f64 = 0.015;
-const NUMBER: f64 = (PHOTON_NUMBER * ROUND_NUMBER) as f64;
 
 fn render(
-    pic: &[Arc<Mutex<Vec<Vec<HitPoint>>>>],
+    picture: &[HitPoint],
     output_file: &str,
-    width: u32,
-    height: u32,
+    width: usize,
+    height: usize,
+    number: f64,
+    rounds: usize,
+    settings: &RenderSettings,
 ) -> ImageResult<()> {
-    ImageBuffer::from_fn(width, height, |x, y| {
-        let interval = width as usize / PARALLEL_NUMBER;
-        let dim_1 = x as usize / interval;
-        let dim_2 = x as usize % interval;
-        let point = &pic[dim_1].lock().unwrap()[dim_2][(height - 1 - y) as usize];
-        let area = f64::consts::PI * point.radius * point.radius;
-        Rgb([
-            trunc(point.tau.x() / (area * NUMBER)),
-            trunc(point.tau.y() / (area * NUMBER)),
-            trunc(point.tau.z() / (area * NUMBER)),
-        ])
-    })
-    .save(output_file)?;
-    Ok(())
+    let mut pixels = Vec::with_capacity(width * height);
+    // 图片的y轴朝下，相机的y轴朝上
+    for y in (0..height).rev() {
+        for point in &picture[y * width..(y + 1) * width] {
+            // 核函数在收集圆盘上的积分，Box核就是圆盘的面积
+            let area = settings.kernel.area(point.radius);
+            let pixel = point.tau / (area * number) + point.direct / rounds as f64;
+            // 光谱模式下累加的是XYZ
+            pixels.push(if settings.spectral {
+                xyz_to_rgb(&pixel)
+            } else {
+                pixel
+            });
+        }
+    }
+    save_image(&pixels, width, height, output_file, &settings.tone_mapper)
+}
+
+// out.png在第3轮的中间结果存成out_0003.png
+fn numbered_file(output_file: &str, round: usize) -> String {
+    let path = Path::new(output_file);
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_{:04}.{}", stem, round, extension.to_string_lossy()),
+        None => format!("{}_{:04}", stem, round),
+    };
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
 }
 
-fn photon_trace(group: &Arc<Group>, mut ray: Ray, photon_map: &mut Vec<Photon>) {
+// 光子在介质里散射或者打到表面这么多次以后开始俄罗斯轮盘赌
+const ROULETTE_DEPTH: usize = 10;
+
+fn photon_trace(
+    group: &Arc<Group>,
+    medium: Option<Medium>,
+    bounded_media: bool,
+    mut ray: Ray,
+    surface: &mut dyn FnMut(Photon),
+    volume: &mut dyn FnMut(Photon),
+    settings: &RenderSettings,
+) {
     let mut depth = 0;
+    // 光源可能在带介质的物体里面，场景里有这种物体时先找出起点所在的介质
+    let mut inside = if bounded_media {
+        enclosing_medium(
+            &**group,
+            ray.get_origin(),
+            settings.t_min,
+            settings.max_depth,
+        )
+    } else {
+        None
+    };
     loop {
-        if depth > 100 {
+        if depth > settings.max_depth {
             break;
         }
-        let hit = group.intersect(&ray, T_MIN);
-        if let Some(hit) = hit {
-            let material = hit.get_material();
+        let hit = group.intersect(&ray, settings.t_min);
+        if let Some(medium) = inside.or(medium) {
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |hit| hit.get_t());
+            match medium.sample(&ray, t_max) {
+                Interaction::Scatter(t, weight) => {
+                    let position = ray.point_at_param(t);
+                    let direction = *ray.get_direction();
+                    let albedo = weight * medium.scattering(ray.get_wavelength());
+                    let mut flux = *ray.get_flux() * albedo;
+                    volume(Photon::new(position, direction, flux, ray.get_wavelength()));
+                    depth += 1;
+                    if depth >= ROULETTE_DEPTH {
+                        let h = albedo.max().min(1.);
+                        if rand::random::<f64>() > h {
+                            break;
+                        }
+                        flux /= h;
+                    }
+                    ray.set(position, medium.sample_phase(&direction), flux);
+                    continue;
+                }
+                Interaction::Pass(weight) => ray.set_color(*ray.get_flux() * weight),
+            }
+        }
+        if let Some(mut hit) = hit {
+            let material = hit.get_material().clone();
             let position = ray.point_at_param(hit.get_t());
-            let direction = ray.get_direction();
+            let direction = *ray.get_direction();
+            hit.set_shading_normal(material.shading_normal(&hit, &position), &direction);
             depth += 1;
             if let MaterialType::Diffuse = material.get_type() {
-                photon_map.push(Photon::new(
+                surface(Photon::new(
                     position,
-                    *direction,
-                    *hit.get_normal(),
+                    direction,
                     *ray.get_flux(),
+                    ray.get_wavelength(),
                 ));
             }
-            if !material.bsdf(&mut ray, hit.get_normal(), &position, depth >= 10) {
+            if !material.bsdf(&mut ray, &hit, &position, depth >= ROULETTE_DEPTH) {
+                break;
+            }
+            if hit.leaks(&direction, ray.get_direction()) {
                 break;
             }
+            inside = cross_surface(inside, &hit, &direction, ray.get_direction());
         } else {
             break;
         }
     }
 }
 
+// 相机光线最后停下来收集光子的地方
+enum Gather {
+    Surface(VisiblePoint),
+    Volume(VolumePoint),
+}
+
+// 直接看到的自发光累加到direct里
 fn ray_trace(
     group: &Arc<Group>,
+    light_group: &Arc<Group>,
+    medium: Option<Medium>,
+    bounded_media: bool,
     mut ray: Ray,
-    kd_tree: &Arc<KDTree>,
-    radius: f64,
-    buffer_pixel: &mut HitPoint,
-) {
+    direct: &mut Vector3<f64>,
+    settings: &RenderSettings,
+) -> Option<Gather> {
     let mut depth = 0;
+    // 相机也可能在带介质的物体里面
+    let mut inside = if bounded_media {
+        enclosing_medium(
+            &**group,
+            ray.get_origin(),
+            settings.t_min,
+            settings.max_depth,
+        )
+    } else {
+        None
+    };
     loop {
-        if depth > 100 {
-            break;
+        if depth > settings.max_depth {
+            return None;
         }
-        let hit = group.intersect(&ray, T_MIN);
-        if let Some(hit) = hit {
-            let material = hit.get_material();
-            let color = material.get_color();
-            let position = ray.point_at_param(hit.get_t());
-            depth += 1;
-            match material.get_type() {
-                MaterialType::Diffuse => {
-                    buffer_pixel.radius = radius;
-                    buffer_pixel.pos = Some(position);
-                    kd_tree.search(buffer_pixel, color, hit.get_normal(), ray.get_flux());
-                    break;
+        let hit = group.intersect(&ray, settings.t_min);
+        let light_hit = light_group.intersect(&ray, settings.t_min);
+        // 在介质里先走一段，散射的话就在这里收集体积光子，不再往后追踪
+        if let Some(medium) = inside.or(medium) {
+            let t_max = hit
+                .iter()
+                .chain(light_hit.iter())
+                .map(|hit| hit.get_t())
+                .fold(f64::INFINITY, f64::min);
+            match medium.sample(&ray, t_max) {
+                Interaction::Scatter(t, weight) => {
+                    return Some(Gather::Volume(VolumePoint {
+                        pos: ray.point_at_param(t),
+                        direction: *ray.get_direction(),
+                        scale: *ray.get_flux() * weight,
+                        medium,
+                        wavelength: ray.get_wavelength(),
+                    }));
                 }
-                MaterialType::Specular | MaterialType::Refraction => {
-                    if !material.bsdf(&mut ray, hit.get_normal(), &position, depth >= 20) {
-                        break;
-                    }
+                Interaction::Pass(weight) => ray.set_color(*ray.get_flux() * weight),
+            }
+        }
+        // 再看是不是直接看到了光源，光源的外形只有正面发光
+        if let Some(light_hit) = light_hit {
+            if hit
+                .as_ref()
+                .map_or(true, |hit| light_hit.get_t() < hit.get_t())
+            {
+                if light_hit.get_normal().dot(*ray.get_direction()) < 0. {
+                    let emission = light_hit
+                        .get_material()
+                        .spectral_emission(ray.get_wavelength());
+                    *direct += to_pixel(ray.get_wavelength(), &(*ray.get_flux() * emission));
                 }
+                return None;
+            }
+        }
+        let mut hit = hit?;
+        let material = hit.get_material().clone();
+        let position = ray.point_at_param(hit.get_t());
+        let direction = *ray.get_direction();
+        hit.set_shading_normal(material.shading_normal(&hit, &position), &direction);
+        let color = material.get_color(&hit, &position);
+        let emission = material.spectral_emission(ray.get_wavelength());
+        *direct += to_pixel(ray.get_wavelength(), &(*ray.get_flux() * emission));
+        depth += 1;
+        match material.get_type() {
+            MaterialType::Diffuse => {
+                return Some(Gather::Surface(VisiblePoint {
+                    pos: position,
+                    color,
+                    normal: *hit.get_normal(),
+                    geometric_normal: *hit.get_geometric_normal(),
+                    scale: *ray.get_flux(),
+                    wavelength: ray.get_wavelength(),
+                }));
+            }
+            MaterialType::Specular | MaterialType::Refraction | MaterialType::Glossy => {
+                if !material.bsdf(&mut ray, &hit, &position, depth >= 20) {
+                    return None;
+                }
+                if hit.leaks(&direction, ray.get_direction()) {
+                    return None;
+                }
+                inside = cross_surface(inside, &hit, &direction, ray.get_direction());
             }
-        } else {
-            //没交上
-            break;
         }
     }
 }
 
+// 每个线程发一部分光子；给了可见点就直接累加上去，否则各自存到自己的缓冲区里，
+// 最后再合并，表面和介质里的光子分开存
+fn photon_pass(
+    group: &Arc<Group>,
+    lights: &[Arc<dyn Light + Send + Sync>],
+    medium: Option<Medium>,
+    bounded_media: bool,
+    visible_points: Option<&VisiblePoints>,
+    settings: &RenderSettings,
+) -> (Vec<Photon>, Vec<Photon>) {
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..settings.parallel_number)
+            .map(|i| {
+                s.spawn(move || {
+                    let begin = settings.photon_number * i / settings.parallel_number;
+                    let end = settings.photon_number * (i + 1) / settings.parallel_number;
+                    let mut buffer: Vec<Photon> = Vec::new();
+                    let mut volume_buffer: Vec<Photon> = Vec::new();
+                    let mut surface = |photon: Photon| match visible_points {
+                        Some(points) => points.deposit(&photon),
+                        None => buffer.push(photon),
+                    };
+                    let mut volume = |photon: Photon| match visible_points {
+                        Some(points) => points.deposit_volume(&photon),
+                        None => volume_buffer.push(photon),
+                    };
+                    for light in lights {
+                        for _ in begin..end {
+                            let ray = if settings.spectral {
+                                light.get_spectral_ray(sample_wavelength(0, 1))
+                            } else {
+                                light.get_ray()
+                            };
+                            photon_trace(
+                                group,
+                                medium,
+                                bounded_media,
+                                ray,
+                                &mut surface,
+                                &mut volume,
+                                settings,
+                            );
+                        }
+                    }
+                    (buffer, volume_buffer)
+                })
+            })
+            .collect();
+        let buffers: Vec<(Vec<Photon>, Vec<Photon>)> =
+            handles.into_iter().map(|h| h.join().unwrap()).collect();
+        let mut photon_map = Vec::with_capacity(buffers.iter().map(|b| b.0.len()).sum());
+        let mut volume_map = Vec::with_capacity(buffers.iter().map(|b| b.1.len()).sum());
+        for (buffer, volume_buffer) in buffers {
+            photon_map.extend(buffer);
+            volume_map.extend(volume_buffer);
+        }
+        (photon_map, volume_map)
+    })
+}
+
+// 一轮的相机光线：给了光子图就直接收集并更新每个像素，
+// 否则只记下自发光，把可见点都存下来返回
+fn camera_pass(
+    scene: &SceneParser,
+    picture: &Mutex<Vec<HitPoint>>,
+    round: usize,
+    photon_maps: Option<(&(dyn PhotonMap + Sync), &VolumeMap)>,
+    settings: &RenderSettings,
+) -> (PixelPoints<VisiblePoint>, PixelPoints<VolumePoint>) {
+    let width = scene.camera.get_width() as usize;
+    let height = scene.camera.get_height() as usize;
+    let queue = TileQueue::new(width, height);
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..settings.parallel_number)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut surface = Vec::new();
+                    let mut volume = Vec::new();
+                    while let Some(tile) = queue.next() {
+                        // 先把这一块的统计量拷出来，渲染完再一次写回去
+                        let mut pixels: Vec<HitPoint> = {
+                            let picture = picture.lock().unwrap();
+                            tile.pixels().map(|(x, y)| picture[y * width + x]).collect()
+                        };
+                        for ((x, y), picture_pixel) in tile.pixels().zip(pixels.iter_mut()) {
+                            let index = y * width + x;
+                            let mut buffer_pixel = HitPoint::new(picture_pixel.radius);
+                            // 光谱模式下同一个像素的各个采样分层取波长，从随机的一层开始
+                            let offset = if settings.spectral {
+                                rand::random::<usize>() % settings.sample_number
+                            } else {
+                                0
+                            };
+                            for i in 0..settings.sample_number {
+                                let dest_x = x as f64 + rand::random::<f64>();
+                                let dest_y = y as f64 + rand::random::<f64>();
+                                let mut ray = scene
+                                    .camera
+                                    .generate_ray(&Vector2::<f64>::from([dest_x, dest_y]));
+                                ray.set_color(*ray.get_flux() / (settings.sample_number as f64));
+                                if settings.spectral {
+                                    ray.set_wavelength(sample_wavelength(
+                                        (i + offset) % settings.sample_number,
+                                        settings.sample_number,
+                                    ));
+                                }
+                                let gather = ray_trace(
+                                    &scene.group,
+                                    &scene.light_group,
+                                    scene.medium,
+                                    scene.bounded_media,
+                                    ray,
+                                    &mut buffer_pixel.direct,
+                                    settings,
+                                );
+                                match (gather, photon_maps) {
+                                    (Some(Gather::Surface(point)), Some((photon_map, _))) => {
+                                        photon_map.search(
+                                            &mut buffer_pixel,
+                                            &point,
+                                            &settings.kernel,
+                                        )
+                                    }
+                                    (Some(Gather::Volume(point)), Some((_, volume_map))) => {
+                                        buffer_pixel.direct += volume_map.radiance(&point)
+                                    }
+                                    (Some(Gather::Surface(point)), None) => {
+                                        surface.push((index, point))
+                                    }
+                                    (Some(Gather::Volume(point)), None) => {
+                                        volume.push((index, point))
+                                    }
+                                    (None, _) => {}
+                                }
+                            }
+                            if photon_maps.is_some() {
+                                picture_pixel.update(&buffer_pixel, round == 0, settings.alpha);
+                            } else {
+                                picture_pixel.direct += buffer_pixel.direct;
+                            }
+                        }
+                        let mut picture = picture.lock().unwrap();
+                        for ((x, y), pixel) in tile.pixels().zip(pixels) {
+                            picture[y * width + x] = pixel;
+                        }
+                    }
+                    (surface, volume)
+                })
+            })
+            .collect();
+        let mut surface = Vec::new();
+        let mut volume = Vec::new();
+        for handle in handles {
+            let (s, v) = handle.join().unwrap();
+            surface.extend(s);
+            volume.extend(v);
+        }
+        (surface, volume)
+    })
+}
+
 fn main() -> Result<(), ImageError> {
-    let mut args = env::args().skip(1);
+    let (positional, options) = parse_args(env::args().skip(1));
+    let mut args = positional.into_iter();
     let scene_file = args.next().expect("No scene file specified.");
     let output_file = args.next().expect("No output file specified.");
     let parser = build_scene_parser(scene_file);
-    let camera = parser.camera;
-    let lights = parser.lights;
-    let group = parser.group;
-    let width = camera.get_width() as usize;
-    let height = camera.get_height() as usize;
-    let mut pictures: Vec<Arc<Mutex<Vec<Vec<HitPoint>>>>> = Vec::with_capacity(PARALLEL_NUMBER);
-    for _ in 0..PARALLEL_NUMBER {
-        pictures.push(Arc::new(Mutex::new(vec![
-            vec![HitPoint::new(); height];
-            width / PARALLEL_NUMBER
-        ])));
-    }
-    let barrier = Arc::new(Barrier::new(PARALLEL_NUMBER + 1));
+    let mut settings = parser.settings;
+    // 检查点相关的选项不属于渲染参数，单独拿出来
+    let mut checkpoint_file: Option<String> = None;
+    let mut resume_file: Option<String> = None;
+    let options: Vec<(String, String)> = options
+        .into_iter()
+        .filter(|(option, value)| match option.as_str() {
+            "--checkpoint" => {
+                checkpoint_file = Some(value.clone());
+                false
+            }
+            "--resume" => {
+                resume_file = Some(value.clone());
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    let width = parser.camera.get_width() as usize;
+    let height = parser.camera.get_height() as usize;
+    // 每个像素的统计量存在一整张图里，下标是y * width + x
+    // emitted是到目前为止每个光源发出的光子数
+    let (start_round, mut emitted, picture) = if let Some(resume_file) = &resume_file {
+        let checkpoint = Checkpoint::load(resume_file)?;
+        assert!(
+            checkpoint.width == width && checkpoint.height == height,
+            "Checkpoint size does not match the scene!"
+        );
+        // 续渲染时沿用检查点里的设置，命令行还可以再改，比如把轮数调大
+        settings = checkpoint.settings;
+        settings.apply_options(&options);
+        assert!(
+            settings.spectral == checkpoint.settings.spectral,
+            "Cannot switch spectral mode when resuming!"
+        );
+        // 已经累加的通量是按原来的核函数加权的
+        assert!(
+            settings.kernel == checkpoint.settings.kernel,
+            "Cannot switch kernel when resuming!"
+        );
+        println!("Resume from round {}", checkpoint.round);
+        (checkpoint.round, checkpoint.emitted, checkpoint.picture)
+    } else {
+        settings.apply_options(&options);
+        (
+            0,
+            0.,
+            vec![HitPoint::new(settings.initial_radius); width * height],
+        )
+    };
+    let checkpoint_file = checkpoint_file.or(resume_file);
+    let picture = Mutex::new(picture);
+    let mut completed = start_round;
+    let mut last_progress = Instant::now();
 
-    for round in 0..ROUND_NUMBER {
-        let mut photon_map: Vec<Photon> = Vec::new();
-        for light in &lights {
-            for _ in 0..PHOTON_NUMBER {
-                let ray = light.get_ray();
-                photon_trace(&group, ray, &mut photon_map);
+    for round in start_round..settings.round_number {
+        let round_start = Instant::now();
+        // 体积光子的半径按轮数缩小，三维的密度估计指数是(alpha - 1) / 3
+        let volume_radius =
+            settings.volume_radius * ((round + 1) as f64).powf((settings.alpha - 1.) / 3.);
+        if settings.visible_points {
+            // 先走相机光线记下可见点，光子直接累加到可见点上
+            let (surface, volume) = camera_pass(&parser, &picture, round, None, &settings);
+            println!(
+                "Round {} camera pass complete, {} visible points",
+                &round,
+                surface.len() + volume.len()
+            );
+            let points = VisiblePoints::new(
+                surface,
+                volume,
+                &picture.lock().unwrap(),
+                volume_radius,
+                settings.kernel,
+                settings.photon_number,
+            );
+            photon_pass(
+                &parser.group,
+                &parser.lights,
+                parser.medium,
+                parser.bounded_media,
+                Some(&points),
+                &settings,
+            );
+            println!("Round {} photon pass complete", &round);
+            let mut picture = picture.lock().unwrap();
+            for (i, pixel) in picture.iter_mut().enumerate() {
+                pixel.update(&points.gathered(i), round == 0, settings.alpha);
             }
+        } else {
+            let (photon_map, volume_map) = photon_pass(
+                &parser.group,
+                &parser.lights,
+                parser.medium,
+                parser.bounded_media,
+                None,
+                &settings,
+            );
+            println!("Round {} photon pass complete", &round);
+            let build_start = Instant::now();
+            // 网格的格子取所有像素里最大的收集半径
+            let max_radius = picture
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|pixel| pixel.radius)
+                .fold(0., f64::max);
+            let photon_map = build_photon_map(photon_map, max_radius, &settings);
+            let volume_map = VolumeMap::new(
+                build_photon_map(volume_map, volume_radius, &settings),
+                volume_radius,
+                settings.kernel,
+                settings.photon_number,
+            );
+            println!(
+                "Round {} {} build complete in {:.2}s",
+                &round,
+                settings.photon_map.name(),
+                build_start.elapsed().as_secs_f64()
+            );
+            camera_pass(
+                &parser,
+                &picture,
+                round,
+                Some((photon_map.as_ref(), &volume_map)),
+                &settings,
+            );
         }
-        println!("Round {} photon pass complete", &round);
-        let kd_tree = KDTree::new(photon_map);
-        let arc_kd_tree = Arc::new(kd_tree);
-        println!("Round {} kd_tree build complete", &round);
-        for (i, picture) in pictures.iter().enumerate() {
-            let group = group.clone();
-            let camera = camera.clone();
-            let arc_kd_tree = arc_kd_tree.clone();
-            let picture = Arc::clone(picture);
-            let barrier = barrier.clone();
-            thread::spawn(move || {
-                let column_begin = width * i / PARALLEL_NUMBER;
-                let column_end = width * (i + 1) / PARALLEL_NUMBER;
-                let mut buffer = vec![vec![HitPoint::new(); height]; column_end - column_begin];
-                let mut picture = picture.lock().unwrap();
-                for (x, global_x) in (column_begin..column_end).enumerate() {
-                    for y in 0..height {
-                        let buffer_pixel = &mut buffer[x][y];
-                        let picture_pixel = &mut picture[x][y];
-                        for _ in 0..SAMPLE_NUMBER {
-                            let dest_x = global_x as f64 + rand::random::<f64>();
-                            let dest_y = y as f64 + rand::random::<f64>();
-                            let mut ray =
-                                camera.generate_ray(&Vector2::<f64>::from([dest_x, dest_y]));
-                            ray.set_color(*ray.get_flux() / (SAMPLE_NUMBER as f64));
-                            ray_trace(
-                                &group,
-                                ray,
-                                &arc_kd_tree,
-                                picture_pixel.radius,
-                                buffer_pixel,
-                            );
-                        }
-                        if round == 0 {
-                            picture_pixel.n = buffer_pixel.n;
-                            picture_pixel.tau = buffer_pixel.tau;
-                        } else if picture_pixel.n + buffer_pixel.n > 0. {
-                            let ratio = (picture_pixel.n + photon::ALPHA * buffer_pixel.n)
-                                / (picture_pixel.n + buffer_pixel.n);
-                            picture_pixel.radius *= f64::sqrt(ratio);
-                            picture_pixel.tau = (picture_pixel.tau + buffer_pixel.tau) * ratio;
-                            picture_pixel.n += buffer_pixel.n * ratio;
-                        }
-                    }
-                }
-                drop(picture);
-                barrier.wait();
-            });
+        emitted += settings.photon_number as f64;
+        completed += 1;
+        println!(
+            "Round {} complete in {:.2}s",
+            &round,
+            round_start.elapsed().as_secs_f64()
+        );
+        // 最后一轮结束后本来就会输出，不用再写中间结果
+        let by_round =
+            settings.progress_interval > 0 && (round + 1) % settings.progress_interval == 0;
+        let by_time = settings.progress_seconds > 0.
+            && last_progress.elapsed().as_secs_f64() >= settings.progress_seconds;
+        if round + 1 < settings.round_number && (by_round || by_time) {
+            let progress_file = if settings.progress_numbered {
+                numbered_file(&output_file, round + 1)
+            } else {
+                output_file.clone()
+            };
+            render(
+                &picture.lock().unwrap(),
+                &progress_file,
+                width,
+                height,
+                emitted,
+                completed,
+                &settings,
+            )?;
+            last_progress = Instant::now();
+            println!("Round {} progress written to {}", &round, &progress_file);
+        }
+        if let Some(checkpoint_file) = &checkpoint_file {
+            if (round + 1) % settings.checkpoint_interval.max(1) == 0
+                || round + 1 == settings.round_number
+            {
+                let checkpoint = Checkpoint {
+                    round: round + 1,
+                    emitted,
+                    width,
+                    height,
+                    settings,
+                    picture: picture.lock().unwrap().clone(),
+                };
+                checkpoint.save(checkpoint_file)?;
+                println!("Round {} checkpoint saved", &round);
+            }
         }
-        barrier.wait();
-        println!("Round {} complete", &round);
     }
-    render(&pictures, &output_file, width as u32, height as u32)?;
+    render(
+        &picture.into_inner().unwrap(),
+        &output_file,
+        width,
+        height,
+        emitted,
+        completed,
+        &settings,
+    )?;
     Ok(())
 }
@@ -1,23 +1,42 @@
 use crate::{
+    hit::Hit,
+    medium::{build_medium, Medium},
+    microfacet::{fresnel_conductor, fresnel_dielectric, reflect, refract, Ggx},
     ray::Ray,
+    spectrum::{tint, to_spectrum, Blackbody},
+    texture::{build_texture, ConstantTexture, Texture},
     utils::{gen_vert, parse_vector},
 };
 use core::f64;
 use json::JsonValue;
 use rand::{thread_rng, Rng};
 use std::sync::Arc;
-use vecmat::{traits::Dot, vector::Vector3, Vector};
+use vecmat::{
+    traits::Dot,
+    vector::{Vector2, Vector3},
+    Vector,
+};
 
 pub trait Material {
-    fn bsdf(
-        &self,
-        ray: &mut Ray,
-        norm: &Vector3<f64>,
-        pos: &Vector3<f64>,
-        russian_roulette: bool,
-    ) -> bool;
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool;
     fn get_type(&self) -> &MaterialType;
-    fn get_color(&self) -> &Vector3<f64>;
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64>;
+    // 没有法线贴图的材质直接用物体给出的法向
+    fn shading_normal(&self, hit: &Hit, _pos: &Vector3<f64>) -> Vector3<f64> {
+        *hit.get_normal()
+    }
+    // 表面自己发出的辐射亮度，不发光的材质是0
+    fn emission(&self) -> Vector3<f64> {
+        Vector3::<f64>::fill(0.)
+    }
+    // 带波长的光线看到的自发光，默认把RGB的自发光上采样
+    fn spectral_emission(&self, wavelength: Option<f64>) -> Vector3<f64> {
+        tint(wavelength, &self.emission())
+    }
+    // 物体里面充满的介质，没有的话光线穿过表面不改变所在的介质
+    fn medium(&self) -> Option<Medium> {
+        None
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -25,35 +44,32 @@ pub enum MaterialType {
     Diffuse,
     Specular,
     Refraction,
+    Glossy, //粗糙的微表面，按分布随机选一个方向继续走
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct DiffuseMaterial {
-    color: Vector3<f64>,
+    texture: Arc<dyn Texture + Send + Sync>,
     material_type: MaterialType,
 }
 
 impl DiffuseMaterial {
-    pub fn new(color: Vector3<f64>) -> Self {
+    pub fn new(texture: Arc<dyn Texture + Send + Sync>) -> Self {
         Self {
-            color,
+            texture,
             material_type: MaterialType::Diffuse,
         }
     }
 }
 
 impl Material for DiffuseMaterial {
-    fn bsdf(
-        &self,
-        ray: &mut Ray,
-        norm: &Vector3<f64>,
-        pos: &Vector3<f64>,
-        russian_roulette: bool,
-    ) -> bool {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
         let mut rng = thread_rng();
+        let norm = hit.get_normal();
+        let color = tint(ray.get_wavelength(), &self.get_color(hit, pos));
         let mut flux = *ray.get_flux();
         if russian_roulette {
-            let h = self.color.max();
+            let h = color.max();
             if rng.gen_range(0. ..1.) > h {
                 return false;
             } else {
@@ -67,45 +83,41 @@ impl Material for DiffuseMaterial {
         let direction_out = f64::cos(theta) * f64::sin(phi) * x_axis
             + f64::sin(theta) * f64::sin(phi) * y_axis
             + f64::cos(phi) * *norm;
-        ray.set(*pos, direction_out, flux * self.color);
+        ray.set(*pos, direction_out, flux * color);
         true
     }
     fn get_type(&self) -> &MaterialType {
         &self.material_type
     }
-    fn get_color(&self) -> &Vector3<f64> {
-        &self.color
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.texture.value(hit.get_texcoord(), pos)
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct SpecularMaterial {
-    color: Vector3<f64>,
+    texture: Arc<dyn Texture + Send + Sync>,
     material_type: MaterialType,
 }
 
 impl SpecularMaterial {
-    pub fn new(color: Vector3<f64>) -> Self {
+    pub fn new(texture: Arc<dyn Texture + Send + Sync>) -> Self {
         Self {
-            color,
+            texture,
             material_type: MaterialType::Specular,
         }
     }
 }
 
 impl Material for SpecularMaterial {
-    fn bsdf(
-        &self,
-        ray: &mut Ray,
-        norm: &Vector3<f64>,
-        pos: &Vector3<f64>,
-        russian_roulette: bool,
-    ) -> bool {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
         let mut rng = thread_rng();
+        let norm = hit.get_normal();
+        let color = tint(ray.get_wavelength(), &self.get_color(hit, pos));
         let direction_in = *ray.get_direction();
         let mut flux = *ray.get_flux();
         if russian_roulette {
-            let h = self.color.max();
+            let h = color.max();
             if rng.gen_range(0. ..1.) > h {
                 return false;
             } else {
@@ -115,48 +127,135 @@ impl Material for SpecularMaterial {
         ray.set(
             *pos,
             direction_in - 2. * norm.dot(direction_in) * *norm,
-            flux * self.color,
+            flux * color,
         );
         true
     }
     fn get_type(&self) -> &MaterialType {
         &self.material_type
     }
-    fn get_color(&self) -> &Vector3<f64> {
-        &self.color
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.texture.value(hit.get_texcoord(), pos)
     }
 }
 
-#[derive(Clone, Copy)]
+// 看不见的表面，光线原样穿过去，只用来给介质划定边界
+#[derive(Clone)]
+pub struct NullMaterial {
+    material_type: MaterialType,
+}
+
+impl NullMaterial {
+    pub fn new() -> Self {
+        Self {
+            material_type: MaterialType::Specular,
+        }
+    }
+}
+
+impl Material for NullMaterial {
+    fn bsdf(&self, ray: &mut Ray, _hit: &Hit, pos: &Vector3<f64>, _russian_roulette: bool) -> bool {
+        ray.set(*pos, *ray.get_direction(), *ray.get_flux());
+        true
+    }
+    fn get_type(&self) -> &MaterialType {
+        &self.material_type
+    }
+    fn get_color(&self, _hit: &Hit, _pos: &Vector3<f64>) -> Vector3<f64> {
+        Vector3::<f64>::fill(1.)
+    }
+}
+
+#[derive(Clone)]
 pub struct RefractionMaterial {
-    color: Vector3<f64>,
+    texture: Arc<dyn Texture + Send + Sync>,
     refr_index: f64,
+    dispersion: Option<Dispersion>, //有的话就不用refr_index
+    absorption: Vector3<f64>,       //介质每单位长度的吸收系数sigma_a
     material_type: MaterialType,
 }
 
 impl RefractionMaterial {
-    pub fn new(color: Vector3<f64>, refr_index: Option<f64>) -> Self {
+    pub fn new(
+        texture: Arc<dyn Texture + Send + Sync>,
+        refr_index: Option<f64>,
+        dispersion: Option<Dispersion>,
+        absorption: Option<Vector3<f64>>,
+    ) -> Self {
         Self {
-            color,
+            texture,
             refr_index: refr_index.unwrap_or(1.5),
+            dispersion,
+            absorption: absorption.unwrap_or_else(|| Vector3::<f64>::fill(0.)),
             material_type: MaterialType::Refraction,
         }
     }
 }
 
+// R、G、B三个通道对应的波长，单位是微米
+pub const CHANNEL_WAVELENGTHS: [f64; 3] = [0.65, 0.55, 0.45];
+
+// 折射率随波长的变化，波长的单位是微米
+#[derive(Clone, Copy)]
+pub enum Dispersion {
+    Cauchy(f64, f64),              //n = A + B / λ²
+    Sellmeier([f64; 3], [f64; 3]), //n² = 1 + Σ Bi λ² / (λ² - Ci)
+}
+
+impl Dispersion {
+    pub fn index(&self, wavelength: f64) -> f64 {
+        let l2 = wavelength * wavelength;
+        match self {
+            Self::Cauchy(a, b) => a + b / l2,
+            Self::Sellmeier(b, c) => f64::sqrt(
+                1. + b
+                    .iter()
+                    .zip(c.iter())
+                    .map(|(b, c)| b * l2 / (l2 - c))
+                    .sum::<f64>(),
+            ),
+        }
+    }
+}
+
+// RGB模式下色散材质第一次打到时随机选一个通道，之后光线只带这个通道的通量（乘3保持无偏），
+// 折射率按这个通道的波长算
+fn channel_index(refr_index: f64, dispersion: &Option<Dispersion>, ray: &mut Ray) -> f64 {
+    let dispersion = match dispersion {
+        Some(dispersion) => dispersion,
+        None => return refr_index,
+    };
+    // 光谱模式下光线本来就只有一个波长
+    if let Some(lambda) = ray.get_wavelength() {
+        return dispersion.index(lambda / 1000.);
+    }
+    let channel = ray.get_channel().unwrap_or_else(|| {
+        let channel = thread_rng().gen_range(0..3);
+        let mut flux = Vector3::<f64>::fill(0.);
+        flux[channel] = ray.get_flux()[channel] * 3.;
+        ray.set_color(flux);
+        ray.set_channel(channel);
+        channel
+    });
+    dispersion.index(CHANNEL_WAVELENGTHS[channel])
+}
+
+// 光线从里面打到表面上，说明刚在介质里走完了从起点到pos这一段，按Beer–Lambert定律衰减
+fn transmittance(absorption: &Vector3<f64>, ray: &Ray, pos: &Vector3<f64>) -> Vector3<f64> {
+    let dist = (*pos - *ray.get_origin()).length();
+    absorption.map(|sigma| f64::exp(-sigma * dist))
+}
+
 impl Material for RefractionMaterial {
-    fn bsdf(
-        &self,
-        ray: &mut Ray,
-        norm: &Vector3<f64>,
-        pos: &Vector3<f64>,
-        russian_roulette: bool,
-    ) -> bool {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        let refr_index = channel_index(self.refr_index, &self.dispersion, ray);
         let mut rng = thread_rng();
+        let norm = hit.get_normal();
+        let color = tint(ray.get_wavelength(), &self.get_color(hit, pos));
         let direction_in = ray.get_direction();
         let mut flux = *ray.get_flux();
         if russian_roulette {
-            let h = self.color.max();
+            let h = color.max();
             if rng.gen_range(0. ..1.) > h {
                 return false;
             } else {
@@ -169,50 +268,560 @@ impl Material for RefractionMaterial {
         } else {
             -1. * *norm
         };
-        let into = norm.dot(nl) > 0.;
-        let into_dir = if into { 1. } else { -1. };
-        let ratio = if into {
-            1. / self.refr_index
-        } else {
-            self.refr_index
-        };
+        // 射入还是射出要按物体朝外的法向判断，三角形的法向总是朝着光线
+        let into = hit.get_outward_normal().dot(*direction_in) < 0.;
+        if !into {
+            flux *= transmittance(&tint(ray.get_wavelength(), &self.absorption), ray, pos);
+        }
+        let ratio = if into { 1. / refr_index } else { refr_index };
         let proj = direction_in.dot(nl);
         let cos_out_sqr = 1. - ratio * ratio * (1. - proj * proj);
         if cos_out_sqr < 0. {
             // 全反射
-            ray.set(*pos, refl_d, flux * self.color);
+            ray.set(*pos, refl_d, flux * color);
         } else {
-            let refr_d =
-                ratio * *direction_in - *norm * into_dir * (proj * ratio + f64::sqrt(cos_out_sqr));
+            let refr_d = ratio * *direction_in - nl * (proj * ratio + f64::sqrt(cos_out_sqr));
             let refr_d = refr_d.normalize();
-            let r_0 = (self.refr_index - 1.) * (self.refr_index - 1.)
-                / (self.refr_index + 1.)
-                / (self.refr_index + 1.);
-            let c = 1. - if into { -1. * proj } else { refr_d.dot(*norm) };
+            let r_0 = (refr_index - 1.) * (refr_index - 1.) / (refr_index + 1.) / (refr_index + 1.);
+            let c = 1. - if into { -proj } else { -refr_d.dot(nl) };
             let r_e = r_0 + (1. - r_0) * c * c * c * c * c;
             if rng.gen_range(0. ..1.) < r_e {
-                ray.set(*pos, refl_d, flux * self.color);
+                ray.set(*pos, refl_d, flux * color);
+            } else {
+                ray.set(*pos, refr_d, flux * color);
+            }
+        }
+        true
+    }
+    fn get_type(&self) -> &MaterialType {
+        &self.material_type
+    }
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.texture.value(hit.get_texcoord(), pos)
+    }
+}
+
+// 以朝着入射一侧的法向为z轴的局部坐标系，x轴尽量沿着物体给出的切线
+fn local_frame(normal: &Vector3<f64>, tangent: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let tangent = *tangent - *normal * normal.dot(*tangent);
+    let tangent = if tangent.square_length() < 1e-12 {
+        gen_vert(normal)
+    } else {
+        tangent.normalize()
+    };
+    (tangent, normal.cross(tangent))
+}
+
+// 按可见法线采样一个反射方向，返回世界坐标里的方向、入射方向和微表面法线夹角的余弦、遮蔽项的权重，
+// 反射到表面下面的就当作被遮挡吸收了，返回None
+fn sample_glossy_reflection(
+    distribution: &Ggx,
+    direction_in: &Vector3<f64>,
+    hit: &Hit,
+) -> Option<(Vector3<f64>, f64, f64)> {
+    let mut rng = thread_rng();
+    let norm = if hit.get_normal().dot(*direction_in) < 0. {
+        *hit.get_normal()
+    } else {
+        -*hit.get_normal()
+    };
+    let (x_axis, y_axis) = local_frame(&norm, hit.get_tangent());
+    let wo = -direction_in.normalize();
+    let wo = Vector3::<f64>::from([wo.dot(x_axis), wo.dot(y_axis), wo.dot(norm)]);
+    let wm =
+        distribution.sample_visible_normal(&wo, rng.gen_range(0. ..1.), rng.gen_range(0. ..1.));
+    let wi = reflect(&wo, &wm);
+    if wi.z() <= 0. {
+        return None;
+    }
+    Some((
+        x_axis * wi.x() + y_axis * wi.y() + norm * wi.z(),
+        wo.dot(wm).clamp(0., 1.),
+        distribution.masking_weight(&wo, &wi),
+    ))
+}
+
+// 常见金属在R、G、B三个波长（约650、550、450nm）上的复折射率eta + ik
+pub fn metal_preset(name: &str) -> (Vector3<f64>, Vector3<f64>) {
+    let (eta, k) = match name {
+        "Gold" => ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+        "Silver" => ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+        "Copper" => ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+        "Aluminium" => ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+        _ => panic!("Unknown metal {}", name),
+    };
+    (Vector3::<f64>::from(eta), Vector3::<f64>::from(k))
+}
+
+// 金属：给了复折射率eta + ik就算精确的菲涅尔项，颜色只是额外的染色；
+// 没给就用Schlick近似，颜色就是正入射的反射率。没给粗糙度就是镜面反射，给了就是GGX微表面
+#[derive(Clone)]
+pub struct ConductorMaterial {
+    texture: Arc<dyn Texture + Send + Sync>,
+    ior: Option<(Vector3<f64>, Vector3<f64>)>, //eta和k
+    distribution: Option<Ggx>,
+    material_type: MaterialType,
+}
+
+impl ConductorMaterial {
+    pub fn new(
+        texture: Arc<dyn Texture + Send + Sync>,
+        ior: Option<(Vector3<f64>, Vector3<f64>)>,
+        distribution: Option<Ggx>,
+    ) -> Self {
+        Self {
+            texture,
+            ior,
+            distribution,
+            material_type: if distribution.is_some() {
+                MaterialType::Glossy
+            } else {
+                MaterialType::Specular
+            },
+        }
+    }
+    // 入射方向和(微表面)法线夹角的余弦是cos_i时的反射率，已经乘上了颜色；
+    // 光谱模式下把eta和k上采样到光线的波长
+    fn reflectance(
+        &self,
+        color: &Vector3<f64>,
+        cos_i: f64,
+        wavelength: Option<f64>,
+    ) -> Vector3<f64> {
+        let (eta, k) = match &self.ior {
+            Some(ior) => ior,
+            None => {
+                let c = 1. - cos_i;
+                return *color + (Vector3::<f64>::fill(1.) - *color) * (c * c * c * c * c);
+            }
+        };
+        let fresnel = if let Some(lambda) = wavelength {
+            Vector3::<f64>::fill(fresnel_conductor(
+                cos_i,
+                to_spectrum(eta, lambda),
+                to_spectrum(k, lambda),
+            ))
+        } else {
+            Vector3::<f64>::from([
+                fresnel_conductor(cos_i, eta.x(), k.x()),
+                fresnel_conductor(cos_i, eta.y(), k.y()),
+                fresnel_conductor(cos_i, eta.z(), k.z()),
+            ])
+        };
+        *color * fresnel
+    }
+}
+
+impl Material for ConductorMaterial {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        let mut rng = thread_rng();
+        let direction_in = *ray.get_direction();
+        let (direction_out, cos_i, weight) = if let Some(distribution) = &self.distribution {
+            match sample_glossy_reflection(distribution, &direction_in, hit) {
+                Some(sample) => sample,
+                None => return false,
+            }
+        } else {
+            let norm = hit.get_normal();
+            let cos_i = -norm.dot(direction_in.normalize());
+            (
+                direction_in - 2. * norm.dot(direction_in) * *norm,
+                cos_i.abs(),
+                1.,
+            )
+        };
+        let color = tint(ray.get_wavelength(), &self.get_color(hit, pos));
+        let color = self.reflectance(&color, cos_i, ray.get_wavelength());
+        let mut flux = *ray.get_flux();
+        if russian_roulette {
+            let h = color.max();
+            if rng.gen_range(0. ..1.) > h {
+                return false;
+            } else {
+                flux = flux.map(|x| x / h);
+            }
+        }
+        ray.set(*pos, direction_out, flux * color * weight);
+        true
+    }
+    fn get_type(&self) -> &MaterialType {
+        &self.material_type
+    }
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.texture.value(hit.get_texcoord(), pos)
+    }
+}
+
+// 粗糙玻璃，按微表面法线上的菲涅尔反射率随机选反射还是折射
+#[derive(Clone)]
+pub struct RoughDielectricMaterial {
+    texture: Arc<dyn Texture + Send + Sync>,
+    refr_index: f64,
+    dispersion: Option<Dispersion>,
+    absorption: Vector3<f64>,
+    distribution: Ggx,
+    material_type: MaterialType,
+}
+
+impl RoughDielectricMaterial {
+    pub fn new(
+        texture: Arc<dyn Texture + Send + Sync>,
+        refr_index: Option<f64>,
+        dispersion: Option<Dispersion>,
+        absorption: Option<Vector3<f64>>,
+        distribution: Ggx,
+    ) -> Self {
+        Self {
+            texture,
+            refr_index: refr_index.unwrap_or(1.5),
+            dispersion,
+            absorption: absorption.unwrap_or_else(|| Vector3::<f64>::fill(0.)),
+            distribution,
+            material_type: MaterialType::Glossy,
+        }
+    }
+}
+
+impl Material for RoughDielectricMaterial {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        let refr_index = channel_index(self.refr_index, &self.dispersion, ray);
+        let mut rng = thread_rng();
+        let color = tint(ray.get_wavelength(), &self.get_color(hit, pos));
+        let direction_in = *ray.get_direction();
+        let mut flux = *ray.get_flux();
+        if russian_roulette {
+            let h = color.max();
+            if rng.gen_range(0. ..1.) > h {
+                return false;
             } else {
-                ray.set(*pos, refr_d, flux * self.color);
+                flux = flux.map(|x| x / h);
             }
         }
+        // 射入还是射出要按物体朝外的法向判断，三角形的法向总是朝着光线
+        let into = hit.get_outward_normal().dot(direction_in) < 0.;
+        if !into {
+            flux *= transmittance(&tint(ray.get_wavelength(), &self.absorption), ray, pos);
+        }
+        let norm = if hit.get_normal().dot(direction_in) < 0. {
+            *hit.get_normal()
+        } else {
+            -*hit.get_normal()
+        };
+        let ratio = if into { 1. / refr_index } else { refr_index };
+        let (x_axis, y_axis) = local_frame(&norm, hit.get_tangent());
+        let wo = -direction_in.normalize();
+        let wo = Vector3::<f64>::from([wo.dot(x_axis), wo.dot(y_axis), wo.dot(norm)]);
+        let wm = self.distribution.sample_visible_normal(
+            &wo,
+            rng.gen_range(0. ..1.),
+            rng.gen_range(0. ..1.),
+        );
+        let fresnel = fresnel_dielectric(wo.dot(wm), 1. / ratio);
+        let refracted = if rng.gen_range(0. ..1.) < fresnel {
+            None
+        } else {
+            refract(&wo, &wm, ratio)
+        };
+        let wi = match refracted {
+            Some(wi) if wi.z() < 0. => wi,
+            Some(_) => return false,
+            None => {
+                let wi = reflect(&wo, &wm);
+                if wi.z() <= 0. {
+                    return false;
+                }
+                wi
+            }
+        };
+        ray.set(
+            *pos,
+            (x_axis * wi.x() + y_axis * wi.y() + norm * wi.z()).normalize(),
+            flux * color * self.distribution.masking_weight(&wo, &wi),
+        );
         true
     }
     fn get_type(&self) -> &MaterialType {
         &self.material_type
     }
-    fn get_color(&self) -> &Vector3<f64> {
-        &self.color
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.texture.value(hit.get_texcoord(), pos)
+    }
+}
+
+const BUMP_DELTA: f64 = 1e-3;
+
+pub enum NormalMap {
+    // 切空间法线贴图，rgb对应切线、副切线、法向
+    Normal(Arc<dyn Texture + Send + Sync>),
+    // 高度图和凹凸强度
+    Bump(Arc<dyn Texture + Send + Sync>, f64),
+}
+
+// 给任意材质套一层法线贴图，其余行为全部交给里面的材质
+pub struct NormalMappedMaterial {
+    material: Arc<dyn Material + Send + Sync>,
+    map: NormalMap,
+}
+
+impl NormalMappedMaterial {
+    pub fn new(material: Arc<dyn Material + Send + Sync>, map: NormalMap) -> Self {
+        Self { material, map }
+    }
+}
+
+impl Material for NormalMappedMaterial {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        self.material.bsdf(ray, hit, pos, russian_roulette)
+    }
+    fn get_type(&self) -> &MaterialType {
+        self.material.get_type()
+    }
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.material.get_color(hit, pos)
     }
+    fn emission(&self) -> Vector3<f64> {
+        self.material.emission()
+    }
+    fn spectral_emission(&self, wavelength: Option<f64>) -> Vector3<f64> {
+        self.material.spectral_emission(wavelength)
+    }
+    fn medium(&self) -> Option<Medium> {
+        self.material.medium()
+    }
+    fn shading_normal(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        let normal = self.material.shading_normal(hit, pos);
+        // Gram-Schmidt得到正交的切空间
+        let tangent = *hit.get_tangent() - normal * normal.dot(*hit.get_tangent());
+        let tangent = if tangent.square_length() < 1e-12 {
+            gen_vert(&normal)
+        } else {
+            tangent.normalize()
+        };
+        let bitangent: Vector3<f64> = normal.cross(tangent);
+        let texcoord = *hit.get_texcoord();
+        match &self.map {
+            NormalMap::Normal(texture) => {
+                let value = texture.value(&texcoord, pos).map(|x| x * 2. - 1.);
+                (tangent * value.x() + bitangent * value.y() + normal * value.z()).normalize()
+            }
+            NormalMap::Bump(texture, strength) => {
+                let height = |du: f64, dv: f64| {
+                    let uv = texcoord + Vector2::<f64>::from([du, dv]);
+                    let p = *pos + tangent * du + bitangent * dv;
+                    texture.value(&uv, &p).sum() / 3.
+                };
+                let h = height(0., 0.);
+                let dh_du = (height(BUMP_DELTA, 0.) - h) / BUMP_DELTA;
+                let dh_dv = (height(0., BUMP_DELTA) - h) / BUMP_DELTA;
+                (normal - (tangent * dh_du + bitangent * dh_dv) * *strength).normalize()
+            }
+        }
+    }
+}
+
+// 给任意材质加上自发光，挂了这种材质的物体会变成面光源；
+// 给了黑体时emission当作染色，RGB模式下乘黑体的颜色，光谱模式下上采样之后乘黑体的光谱
+pub struct EmissiveMaterial {
+    material: Arc<dyn Material + Send + Sync>,
+    emission: Vector3<f64>,
+    color: Vector3<f64>,
+    blackbody: Option<Blackbody>,
+}
+
+impl EmissiveMaterial {
+    pub fn new(
+        material: Arc<dyn Material + Send + Sync>,
+        emission: Vector3<f64>,
+        scale: Option<f64>,
+        blackbody: Option<Blackbody>,
+    ) -> Self {
+        let color = emission * scale.unwrap_or(1.);
+        Self {
+            material,
+            emission: blackbody.map_or(color, |blackbody| color * blackbody.rgb()),
+            color,
+            blackbody,
+        }
+    }
+}
+
+impl Material for EmissiveMaterial {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        self.material.bsdf(ray, hit, pos, russian_roulette)
+    }
+    fn get_type(&self) -> &MaterialType {
+        self.material.get_type()
+    }
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.material.get_color(hit, pos)
+    }
+    fn shading_normal(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.material.shading_normal(hit, pos)
+    }
+    fn emission(&self) -> Vector3<f64> {
+        self.emission
+    }
+    fn spectral_emission(&self, wavelength: Option<f64>) -> Vector3<f64> {
+        match (wavelength, self.blackbody) {
+            (Some(lambda), Some(blackbody)) => {
+                Vector3::<f64>::fill(to_spectrum(&self.color, lambda) * blackbody.value(lambda))
+            }
+            _ => tint(wavelength, &self.emission),
+        }
+    }
+    fn medium(&self) -> Option<Medium> {
+        self.material.medium()
+    }
+}
+
+// 给任意材质加上里面的介质，光线透射进物体之后就在介质里走
+pub struct MediumMaterial {
+    material: Arc<dyn Material + Send + Sync>,
+    medium: Medium,
+}
+
+impl MediumMaterial {
+    pub fn new(material: Arc<dyn Material + Send + Sync>, medium: Medium) -> Self {
+        Self { material, medium }
+    }
+}
+
+impl Material for MediumMaterial {
+    fn bsdf(&self, ray: &mut Ray, hit: &Hit, pos: &Vector3<f64>, russian_roulette: bool) -> bool {
+        self.material.bsdf(ray, hit, pos, russian_roulette)
+    }
+    fn get_type(&self) -> &MaterialType {
+        self.material.get_type()
+    }
+    fn get_color(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.material.get_color(hit, pos)
+    }
+    fn shading_normal(&self, hit: &Hit, pos: &Vector3<f64>) -> Vector3<f64> {
+        self.material.shading_normal(hit, pos)
+    }
+    fn emission(&self) -> Vector3<f64> {
+        self.material.emission()
+    }
+    fn spectral_emission(&self, wavelength: Option<f64>) -> Vector3<f64> {
+        self.material.spectral_emission(wavelength)
+    }
+    fn medium(&self) -> Option<Medium> {
+        Some(self.medium)
+    }
+}
+
+fn build_distribution(material_attr: &JsonValue) -> Ggx {
+    Ggx::new(
+        material_attr["Roughness"].as_f64().unwrap(),
+        material_attr["Anisotropy"].as_f64(),
+    )
 }
 
 pub fn build_material(material_attr: &JsonValue) -> Arc<dyn Material + Send + Sync> {
     let material_type = material_attr["Type"].as_str().unwrap();
-    let color = parse_vector(&material_attr["Color"]);
-    match material_type {
-        "DIFF" => Arc::new(DiffuseMaterial::new(color)),
-        "SPEC" => Arc::new(SpecularMaterial::new(color)),
-        "REFR" => Arc::new(RefractionMaterial::new(color, None)),
+    // 有Texture就用贴图，否则用纯色，给了复折射率的金属和介质边界可以不写颜色
+    let has_ior = material_attr["Metal"].is_string() || material_attr["Eta"].is_array();
+    let texture: Arc<dyn Texture + Send + Sync> = if material_attr["Texture"].is_object() {
+        build_texture(&material_attr["Texture"], true)
+    } else if material_attr["Color"].is_null()
+        && (material_type == "CONDUCTOR" || material_type == "NULL" || has_ior)
+    {
+        Arc::new(ConstantTexture::new(Vector3::<f64>::fill(1.)))
+    } else {
+        Arc::new(ConstantTexture::new(parse_vector(&material_attr["Color"])))
+    };
+    // 玻璃的吸收系数，可以用AbsorptionScale整体缩放
+    let absorption = if material_attr["Absorption"].is_array() {
+        Some(
+            parse_vector(&material_attr["Absorption"])
+                * material_attr["AbsorptionScale"].as_f64().unwrap_or(1.),
+        )
+    } else {
+        None
+    };
+    // 色散系数，Cauchy写[A, B]，Sellmeier写{"B": [B1, B2, B3], "C": [C1, C2, C3]}，波长单位是微米
+    let dispersion = if material_attr["Cauchy"].is_array() {
+        let cauchy = &material_attr["Cauchy"];
+        Some(Dispersion::Cauchy(
+            cauchy[0].as_f64().unwrap(),
+            cauchy[1].as_f64().unwrap(),
+        ))
+    } else if material_attr["Sellmeier"].is_object() {
+        let sellmeier = &material_attr["Sellmeier"];
+        let b = parse_vector(&sellmeier["B"]);
+        let c = parse_vector(&sellmeier["C"]);
+        Some(Dispersion::Sellmeier(
+            [b.x(), b.y(), b.z()],
+            [c.x(), c.y(), c.z()],
+        ))
+    } else {
+        None
+    };
+    let material: Arc<dyn Material + Send + Sync> = match material_type {
+        "DIFF" => Arc::new(DiffuseMaterial::new(texture)),
+        "SPEC" => Arc::new(SpecularMaterial::new(texture)),
+        "REFR" => Arc::new(RefractionMaterial::new(
+            texture,
+            material_attr["IOR"].as_f64(),
+            dispersion,
+            absorption,
+        )),
+        "GLOSSY" | "CONDUCTOR" => {
+            // Metal选预设，或者直接写Eta和K，都没写就用Schlick近似
+            let ior = if let Some(metal) = material_attr["Metal"].as_str() {
+                Some(metal_preset(metal))
+            } else if material_attr["Eta"].is_array() {
+                Some((
+                    parse_vector(&material_attr["Eta"]),
+                    parse_vector(&material_attr["K"]),
+                ))
+            } else {
+                None
+            };
+            // GLOSSY一定是粗糙的，CONDUCTOR写了Roughness才是
+            let distribution =
+                if material_type == "GLOSSY" || material_attr["Roughness"].is_number() {
+                    Some(build_distribution(material_attr))
+                } else {
+                    None
+                };
+            Arc::new(ConductorMaterial::new(texture, ior, distribution))
+        }
+        "GLOSSY_REFR" => Arc::new(RoughDielectricMaterial::new(
+            texture,
+            material_attr["IOR"].as_f64(),
+            dispersion,
+            absorption,
+            build_distribution(material_attr),
+        )),
+        "NULL" => Arc::new(NullMaterial::new()),
         _ => panic!("Wrong material type!"),
+    };
+    let material: Arc<dyn Material + Send + Sync> = if material_attr["NormalMap"].is_object() {
+        let map = build_texture(&material_attr["NormalMap"], false);
+        Arc::new(NormalMappedMaterial::new(material, NormalMap::Normal(map)))
+    } else if material_attr["BumpMap"].is_object() {
+        let map = build_texture(&material_attr["BumpMap"], false);
+        let strength = material_attr["BumpScale"].as_f64().unwrap_or(1.);
+        Arc::new(NormalMappedMaterial::new(
+            material,
+            NormalMap::Bump(map, strength),
+        ))
+    } else {
+        material
+    };
+    let material: Arc<dyn Material + Send + Sync> = match build_medium(&material_attr["Medium"]) {
+        Some(medium) => Arc::new(MediumMaterial::new(material, medium)),
+        None => material,
+    };
+    if material_attr["Emission"].is_array() {
+        Arc::new(EmissiveMaterial::new(
+            material,
+            parse_vector(&material_attr["Emission"]),
+            material_attr["EmissionScale"].as_f64(),
+            None,
+        ))
+    } else {
+        material
     }
 }
@@ -0,0 +1,220 @@
+use crate::{
+    hit::Hit,
+    kernel::Kernel,
+    object3d::Object3d,
+    photon::{Photon, PhotonMap},
+    ray::Ray,
+    spectrum::{bin, tint, to_xyz, BINS},
+    utils::{gen_vert, parse_vector},
+};
+use core::f64;
+use json::JsonValue;
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+use vecmat::{traits::Dot, vector::Vector3};
+
+// 均匀介质，系数都是每单位长度的，g是Henyey–Greenstein相函数的不对称参数
+#[derive(Clone, Copy)]
+pub struct Medium {
+    scattering: Vector3<f64>,
+    absorption: Vector3<f64>,
+    g: f64,
+}
+
+// 沿光线采样一次自由程的结果，权重是透射率除以采样的概率
+pub enum Interaction {
+    Scatter(f64, Vector3<f64>), //在光线参数t处散射
+    Pass(Vector3<f64>),         //一直走到了表面
+}
+
+impl Medium {
+    pub fn new(scattering: Vector3<f64>, absorption: Vector3<f64>, g: Option<f64>) -> Self {
+        Self {
+            scattering,
+            absorption,
+            g: g.unwrap_or(0.),
+        }
+    }
+    pub fn scattering(&self, wavelength: Option<f64>) -> Vector3<f64> {
+        tint(wavelength, &self.scattering)
+    }
+    // t_max是到最近表面的光线参数；各通道的消光系数不同时按平均值采样距离
+    pub fn sample(&self, ray: &Ray, t_max: f64) -> Interaction {
+        let sigma_t = tint(ray.get_wavelength(), &(self.scattering + self.absorption));
+        let density = sigma_t.sum() / 3.;
+        if density <= 0. {
+            return Interaction::Pass(Vector3::<f64>::fill(1.));
+        }
+        // 光线的方向不一定是单位向量，距离要换算成光线参数
+        let speed = ray.get_direction().length();
+        let dist = -f64::ln(1. - thread_rng().gen_range(0.0..1.0)) / density;
+        let transmittance = |dist: f64| sigma_t.map(|sigma| f64::exp(-sigma * dist));
+        if dist < t_max * speed {
+            let pdf = density * f64::exp(-density * dist);
+            Interaction::Scatter(dist / speed, transmittance(dist) / pdf)
+        } else {
+            let dist = t_max * speed;
+            Interaction::Pass(transmittance(dist) / f64::exp(-density * dist))
+        }
+    }
+    // cos_theta是入射和出射的传播方向的夹角余弦
+    pub fn phase(&self, cos_theta: f64) -> f64 {
+        let denom = 1. + self.g * self.g - 2. * self.g * cos_theta;
+        (1. - self.g * self.g) / (4. * f64::consts::PI * denom * f64::sqrt(denom))
+    }
+    // 按相函数采样出射方向，相函数和概率密度相等，权重是1
+    pub fn sample_phase(&self, direction: &Vector3<f64>) -> Vector3<f64> {
+        let mut rng = thread_rng();
+        let u: f64 = rng.gen_range(0.0..1.0);
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1. - 2. * u
+        } else {
+            let s = (1. - g * g) / (1. - g + 2. * g * u);
+            (1. + g * g - s * s) / (2. * g)
+        }
+        .clamp(-1., 1.);
+        let sin_theta = f64::sqrt(1. - cos_theta * cos_theta);
+        let phi = 2. * f64::consts::PI * rng.gen_range(0.0..1.0);
+        let w = direction.normalize();
+        let u_axis = gen_vert(&w);
+        let v_axis: Vector3<f64> = w.cross(u_axis);
+        u_axis * (sin_theta * f64::cos(phi)) + v_axis * (sin_theta * f64::sin(phi)) + w * cos_theta
+    }
+}
+
+// 相机光线在介质里散射的点，体积光子在这里被收集
+#[derive(Clone, Copy)]
+pub struct VolumePoint {
+    pub pos: Vector3<f64>,
+    pub direction: Vector3<f64>, //相机光线的传播方向
+    pub scale: Vector3<f64>,     //相机光线的通量乘上自由程采样的权重
+    pub medium: Medium,
+    pub wavelength: Option<f64>,
+}
+
+impl VolumePoint {
+    // 一个体积光子按核函数加权后散射到相机方向的贡献，还没有除以核函数在收集球上的积分
+    // 和发出的光子数，光子的通量里已经乘过散射系数；收集球外面的光子返回None
+    pub fn contribution(
+        &self,
+        radius: f64,
+        kernel: &Kernel,
+        photon: &Photon,
+    ) -> Option<Vector3<f64>> {
+        let weight = kernel.weight((photon.pos() - self.pos).square_length(), radius)?;
+        let cos_theta = -photon.dir().dot(self.direction.normalize());
+        let value = self.scale * photon.flux() * self.medium.phase(cos_theta) * weight;
+        // 光谱模式下和表面一样只收同一个波段里的光子
+        Some(match (self.wavelength, photon.wavelength()) {
+            (Some(camera), Some(lambda)) if bin(camera) == bin(lambda) => {
+                to_xyz(lambda, value.x() * BINS as f64)
+            }
+            (Some(_), _) => Vector3::<f64>::fill(0.),
+            (None, _) => value,
+        })
+    }
+}
+
+// 半径为radius的收集球里加权后的光子换成辐射亮度要除的数
+pub fn volume_normalization(radius: f64, kernel: &Kernel, photon_number: usize) -> f64 {
+    kernel.volume(radius) * photon_number as f64
+}
+
+// 一轮里存下的体积光子，以及这一轮的查询半径
+pub struct VolumeMap {
+    tree: Arc<dyn PhotonMap + Send + Sync>,
+    radius: f64,
+    kernel: Kernel,
+    normalization: f64,
+}
+
+impl VolumeMap {
+    pub fn new(
+        tree: Arc<dyn PhotonMap + Send + Sync>,
+        radius: f64,
+        kernel: Kernel,
+        photon_number: usize,
+    ) -> Self {
+        Self {
+            tree,
+            radius,
+            kernel,
+            normalization: volume_normalization(radius, &kernel, photon_number),
+        }
+    }
+    // 用收集球里的体积光子估计散射到相机的辐射亮度
+    pub fn radiance(&self, point: &VolumePoint) -> Vector3<f64> {
+        let mut ret = Vector3::<f64>::fill(0.);
+        self.tree
+            .for_each_candidate(&point.pos, self.radius, &mut |photon| {
+                if let Some(value) = point.contribution(self.radius, &self.kernel, photon) {
+                    ret += value;
+                }
+            });
+        ret / self.normalization
+    }
+}
+
+// 光线透射穿过了带介质的物体表面时，返回光线接下来所在的介质，反射时不变；
+// 按物体朝外的法向判断，出射方向和它相反就是进到了物体里面。介质不能嵌套
+pub fn cross_surface(
+    inside: Option<Medium>,
+    hit: &Hit,
+    direction_in: &Vector3<f64>,
+    direction_out: &Vector3<f64>,
+) -> Option<Medium> {
+    let normal = hit.get_outward_normal();
+    let cos_out = normal.dot(*direction_out);
+    if normal.dot(*direction_in) * cos_out <= 0. {
+        return inside;
+    }
+    match hit.get_material().medium() {
+        Some(medium) if cos_out < 0. => Some(medium),
+        Some(_) => None,
+        None => inside,
+    }
+}
+
+// 光线起点所在的物体里的介质：沿固定方向穿过路上的表面，第一个带介质的表面如果是从里面穿出去的，
+// 起点就在这个物体里面
+pub fn enclosing_medium(
+    group: &dyn Object3d,
+    origin: &Vector3<f64>,
+    t_min: f64,
+    max_depth: usize,
+) -> Option<Medium> {
+    let direction = Vector3::<f64>::from([0.48, 0.6, 0.64]);
+    let mut ray = Ray::new(*origin, direction, None);
+    for _ in 0..max_depth {
+        let hit = group.intersect(&ray, t_min)?;
+        if let Some(medium) = hit.get_material().medium() {
+            return if hit.get_outward_normal().dot(direction) > 0. {
+                Some(medium)
+            } else {
+                None
+            };
+        }
+        ray = Ray::new(ray.point_at_param(hit.get_t()), direction, None);
+    }
+    None
+}
+
+pub fn build_medium(medium_attr: &JsonValue) -> Option<Medium> {
+    if !medium_attr.is_object() {
+        return None;
+    }
+    let scale = medium_attr["Scale"].as_f64().unwrap_or(1.);
+    let vector = |key: &str| {
+        if medium_attr[key].is_array() {
+            parse_vector(&medium_attr[key]) * scale
+        } else {
+            Vector3::<f64>::fill(0.)
+        }
+    };
+    Some(Medium::new(
+        vector("Scattering"),
+        vector("Absorption"),
+        medium_attr["G"].as_f64(),
+    ))
+}
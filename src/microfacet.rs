@@ -0,0 +1,106 @@
+use core::f64;
+use num_complex::Complex;
+use vecmat::{traits::Dot, vector::Vector3};
+
+// GGX法线分布，局部坐标系里z轴是宏观法向，x轴是切线
+#[derive(Clone, Copy)]
+pub struct Ggx {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl Ggx {
+    // roughness按感知的线性取平方，anisotropy在[-1, 1]之间，正的时候沿切线方向更粗糙
+    pub fn new(roughness: f64, anisotropy: Option<f64>) -> Self {
+        let alpha = (roughness * roughness).max(1e-4);
+        let aspect = f64::sqrt(1. - 0.9 * anisotropy.unwrap_or(0.).abs());
+        let (alpha_x, alpha_y) = if anisotropy.unwrap_or(0.) >= 0. {
+            (alpha / aspect, alpha * aspect)
+        } else {
+            (alpha * aspect, alpha / aspect)
+        };
+        Self {
+            alpha_x: alpha_x.max(1e-4),
+            alpha_y: alpha_y.max(1e-4),
+        }
+    }
+    // Smith遮蔽函数里的Λ
+    fn lambda(&self, w: &Vector3<f64>) -> f64 {
+        let z2 = w.z() * w.z();
+        if z2 == 0. {
+            return f64::INFINITY;
+        }
+        let a2 = (self.alpha_x * w.x()).powi(2) + (self.alpha_y * w.y()).powi(2);
+        (-1. + f64::sqrt(1. + a2 / z2)) / 2.
+    }
+    // 按可见法线采样出射方向wi之后的权重G2/G1(wo)，用高度相关的G2
+    pub fn masking_weight(&self, wo: &Vector3<f64>, wi: &Vector3<f64>) -> f64 {
+        let lambda_o = self.lambda(wo);
+        (1. + lambda_o) / (1. + lambda_o + self.lambda(wi))
+    }
+    // Heitz 2018，按wo看得见的微表面法线采样，wo要在上半球
+    pub fn sample_visible_normal(&self, wo: &Vector3<f64>, u1: f64, u2: f64) -> Vector3<f64> {
+        // 拉伸成各向同性的单位粗糙度
+        let vh = Vector3::<f64>::from([self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()])
+            .normalize();
+        let len_sqr = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = if len_sqr > 0. {
+            Vector3::<f64>::from([-vh.y(), vh.x(), 0.]) / f64::sqrt(len_sqr)
+        } else {
+            Vector3::<f64>::from([1., 0., 0.])
+        };
+        let t2: Vector3<f64> = vh.cross(t1);
+        let r = f64::sqrt(u1);
+        let phi = 2. * f64::consts::PI * u2;
+        let p1 = r * f64::cos(phi);
+        let s = 0.5 * (1. + vh.z());
+        let p2 = (1. - s) * f64::sqrt(1. - p1 * p1) + s * r * f64::sin(phi);
+        let nh = t1 * p1 + t2 * p2 + vh * f64::sqrt((1. - p1 * p1 - p2 * p2).max(0.));
+        // 再压回去
+        Vector3::<f64>::from([
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(1e-6),
+        ])
+        .normalize()
+    }
+}
+
+// 非偏振光的菲涅尔反射率，eta是透射一侧和入射一侧折射率之比
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let sin_t_sqr = (1. - cos_i * cos_i) / (eta * eta);
+    if sin_t_sqr >= 1. {
+        return 1.;
+    }
+    let cos_t = f64::sqrt(1. - sin_t_sqr);
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (rs * rs + rp * rp) / 2.
+}
+
+// 金属的菲涅尔反射率，折射率是复数eta + ik，外面是真空
+pub fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let eta = Complex::new(eta, k);
+    let sin_t_sqr = (1. - cos_i * cos_i) / (eta * eta);
+    let cos_t = (Complex::new(1., 0.) - sin_t_sqr).sqrt();
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (rs.norm_sqr() + rp.norm_sqr()) / 2.
+}
+
+pub fn reflect(wo: &Vector3<f64>, wm: &Vector3<f64>) -> Vector3<f64> {
+    *wm * (2. * wo.dot(*wm)) - *wo
+}
+
+// wo和wm在同一侧，ratio是入射一侧和透射一侧折射率之比，全反射时返回None
+pub fn refract(wo: &Vector3<f64>, wm: &Vector3<f64>, ratio: f64) -> Option<Vector3<f64>> {
+    let cos_i = wo.dot(*wm);
+    let cos_t_sqr = 1. - ratio * ratio * (1. - cos_i * cos_i);
+    if cos_t_sqr < 0. {
+        None
+    } else {
+        Some(*wo * -ratio + *wm * (ratio * cos_i - f64::sqrt(cos_t_sqr)))
+    }
+}
@@ -1,44 +1,94 @@
 use crate::{
+    bvh::{BoundingBox, Bvh},
     hit::Hit,
+    lights::{AreaLight, Emitter},
     materials::Material,
     mesh::build_mesh,
     ray::Ray,
-    utils::{gen_rotate, gen_translation},
-    utils::{parse_vector, prior_hit},
+    revsurface::build_revsurface,
+    utils::{gen_rotate, gen_translation, gen_vert, transform_direction, transform_point},
+    utils::{parse_vector, parse_vector2, prior_hit},
 };
 use core::f64;
 use json::JsonValue;
 use std::sync::Arc;
 use vecmat::{
-    matrix::{Matrix3x3, Matrix4x4},
+    matrix::Matrix4x4,
     prelude::One,
     traits::Dot,
-    vector::{Vector3, Vector4},
+    vector::{Vector2, Vector3, Vector4},
     Matrix,
 };
 pub trait Object3d {
     fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit>;
+    fn bounding_box(&self) -> BoundingBox;
+    // 材质发光的物体给出对应的面光源，目前支持球、三角形、圆盘和网格
+    fn area_lights(&self) -> Vec<AreaLight> {
+        Vec::new()
+    }
 }
 pub struct Group {
     group: Vec<Arc<dyn Object3d + Send + Sync>>,
+    bounded: Vec<Arc<dyn Object3d + Send + Sync>>,
+    unbounded: Vec<Arc<dyn Object3d + Send + Sync>>, //平面这种无限大的物体只能挨个求交
+    bvh: Option<Bvh>,
 }
 
 impl Group {
     pub fn new() -> Self {
-        Self { group: Vec::new() }
+        Self {
+            group: Vec::new(),
+            bounded: Vec::new(),
+            unbounded: Vec::new(),
+            bvh: None,
+        }
     }
     pub fn add_object(&mut self, obj: Arc<dyn Object3d + Send + Sync>) {
         self.group.push(obj);
+        self.bvh = None;
+    }
+    // 加完所有物体之后调用，没建的话求交就退回逐个求交
+    pub fn build_bvh(&mut self) {
+        let (bounded, unbounded): (Vec<_>, Vec<_>) = self
+            .group
+            .iter()
+            .cloned()
+            .partition(|object| object.bounding_box().is_finite());
+        let boxes: Vec<BoundingBox> = bounded.iter().map(|x| x.bounding_box()).collect();
+        self.bvh = Some(Bvh::new(&boxes));
+        self.bounded = bounded;
+        self.unbounded = unbounded;
     }
 }
 
 impl Object3d for Group {
     fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
         let mut ret: Option<Hit> = None;
-        for object in &self.group {
-            ret = prior_hit(ret, object.intersect(ray, tmin));
+        if let Some(bvh) = &self.bvh {
+            for object in &self.unbounded {
+                ret = prior_hit(ret, object.intersect(ray, tmin));
+            }
+            let bounded = &self.bounded;
+            prior_hit(
+                ret,
+                bvh.intersect(ray, tmin, |i| bounded[i].intersect(ray, tmin)),
+            )
+        } else {
+            for object in &self.group {
+                ret = prior_hit(ret, object.intersect(ray, tmin));
+            }
+            ret
         }
-        ret
+    }
+    fn bounding_box(&self) -> BoundingBox {
+        self.group
+            .iter()
+            .fold(BoundingBox::empty(), |bbox, object| {
+                bbox.union(&object.bounding_box())
+            })
+    }
+    fn area_lights(&self) -> Vec<AreaLight> {
+        self.group.iter().flat_map(|x| x.area_lights()).collect()
     }
 }
 
@@ -46,14 +96,21 @@ pub struct Plane {
     material: Arc<dyn Material + Send + Sync>,
     normal: Vector3<f64>,
     d: f64,
+    u_axis: Vector3<f64>,
+    v_axis: Vector3<f64>,
 }
 
 impl Plane {
     pub fn new(material: Arc<dyn Material + Send + Sync>, normal: Vector3<f64>, d: f64) -> Self {
+        // 纹理坐标就是交点在平面内两个正交轴上的投影
+        let u_axis = gen_vert(&normal);
+        let v_axis: Vector3<f64> = normal.cross(u_axis).normalize();
         Self {
             material,
             normal,
             d,
+            u_axis,
+            v_axis,
         }
     }
 }
@@ -69,10 +126,98 @@ impl Object3d for Plane {
             if t <= tmin {
                 None
             } else {
-                Some(Hit::new(t, self.material.clone(), self.normal))
+                let point = ray.point_at_param(t);
+                let texcoord =
+                    Vector2::<f64>::from([self.u_axis.dot(point), self.v_axis.dot(point)]);
+                Some(Hit::new(
+                    t,
+                    self.material.clone(),
+                    self.normal,
+                    texcoord,
+                    self.u_axis,
+                ))
             }
         }
     }
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::infinite()
+    }
+}
+
+// 圆盘，法向固定朝normal一侧，不随光线翻转
+pub struct Disk {
+    material: Arc<dyn Material + Send + Sync>,
+    center: Vector3<f64>,
+    normal: Vector3<f64>,
+    radius: f64,
+    u_axis: Vector3<f64>,
+    v_axis: Vector3<f64>,
+}
+
+impl Disk {
+    pub fn new(
+        material: Arc<dyn Material + Send + Sync>,
+        center: Vector3<f64>,
+        normal: Vector3<f64>,
+        radius: f64,
+    ) -> Self {
+        let normal = normal.normalize();
+        let u_axis = gen_vert(&normal);
+        let v_axis: Vector3<f64> = normal.cross(u_axis).normalize();
+        Self {
+            material,
+            center,
+            normal,
+            radius,
+            u_axis,
+            v_axis,
+        }
+    }
+}
+
+impl Object3d for Disk {
+    fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
+        let z2 = self.normal.dot(*ray.get_direction());
+        if f64::abs(z2) <= 1e-5 {
+            return None;
+        }
+        let t = self.normal.dot(self.center - *ray.get_origin()) / z2;
+        if t <= tmin {
+            return None;
+        }
+        let offset = ray.point_at_param(t) - self.center;
+        if offset.square_length() > self.radius * self.radius {
+            return None;
+        }
+        // 纹理坐标按半径归一化到[0, 1]
+        let texcoord = Vector2::<f64>::from([
+            0.5 + 0.5 * self.u_axis.dot(offset) / self.radius,
+            0.5 + 0.5 * self.v_axis.dot(offset) / self.radius,
+        ]);
+        Some(Hit::new(
+            t,
+            self.material.clone(),
+            self.normal,
+            texcoord,
+            self.u_axis,
+        ))
+    }
+    fn bounding_box(&self) -> BoundingBox {
+        let extent = self
+            .normal
+            .map(|x| self.radius * f64::sqrt((1. - x * x).max(0.)));
+        BoundingBox::new(self.center - extent, self.center + extent)
+    }
+    fn area_lights(&self) -> Vec<AreaLight> {
+        emissive_light(
+            &self.material,
+            vec![Emitter::Disk(
+                self.center,
+                self.u_axis * self.radius,
+                self.v_axis * self.radius,
+            )],
+        )
+    }
 }
 
 pub struct Sphere {
@@ -95,6 +240,24 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    // 经纬度参数化，u绕y轴一圈，v从南极到北极
+    fn texcoord(normal: &Vector3<f64>) -> Vector2<f64> {
+        let u = 0.5 + f64::atan2(normal.z(), normal.x()) / (2. * f64::consts::PI);
+        let v = 0.5 + f64::asin(normal.y().clamp(-1., 1.)) / f64::consts::PI;
+        Vector2::<f64>::from([u, v])
+    }
+    // 沿纬线方向，两极退化时随便取一个垂直方向
+    fn tangent(normal: &Vector3<f64>) -> Vector3<f64> {
+        let tangent = Vector3::<f64>::from([-normal.z(), 0., normal.x()]);
+        if tangent.square_length() < 1e-12 {
+            gen_vert(normal)
+        } else {
+            tangent.normalize()
+        }
+    }
+}
+
 impl Object3d for Sphere {
     fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
         let v1 = ray.get_direction();
@@ -109,72 +272,128 @@ impl Object3d for Sphere {
             let qd = f64::sqrt(delta);
             let t1 = (-b - qd) / (2. * a);
             let t2 = (-b + qd) / (2. * a);
-            if t1 >= tmin {
-                let normal = (ray.point_at_param(t1) - self.center).normalize();
-                Some(Hit::new(t1, self.material.clone(), normal))
+            let t = if t1 >= tmin {
+                t1
             } else if t2 >= tmin {
-                let normal = (ray.point_at_param(t2) - self.center).normalize();
-                Some(Hit::new(t2, self.material.clone(), normal))
+                t2
             } else {
-                None
-            }
+                return None;
+            };
+            let normal = (ray.point_at_param(t) - self.center).normalize();
+            Some(Hit::new(
+                t,
+                self.material.clone(),
+                normal,
+                Self::texcoord(&normal),
+                Self::tangent(&normal),
+            ))
         }
     }
+    fn bounding_box(&self) -> BoundingBox {
+        let r = Vector3::<f64>::fill(self.radius);
+        BoundingBox::new(self.center - r, self.center + r)
+    }
+    fn area_lights(&self) -> Vec<AreaLight> {
+        emissive_light(
+            &self.material,
+            vec![Emitter::Sphere(self.center, self.radius)],
+        )
+    }
+}
+
+// 平面、旋转曲面这类不能当面光源的物体不能用发光材质，否则相机看得见它发光，却没有光子从它发出
+pub fn assert_not_emissive(material: &Arc<dyn Material + Send + Sync>, object_type: &str) {
+    assert!(
+        material.emission().max() <= 0.,
+        "{} cannot use an emissive material, only Sphere, Triangle, Disk and Mesh can",
+        object_type
+    );
+}
+
+// 材质不发光就没有面光源
+pub fn emissive_light(
+    material: &Arc<dyn Material + Send + Sync>,
+    emitters: Vec<Emitter>,
+) -> Vec<AreaLight> {
+    let emission = material.emission();
+    if emission.max() > 0. {
+        vec![AreaLight::new(emission, emitters)]
+    } else {
+        Vec::new()
+    }
 }
 
 pub struct Triangle {
     material: Arc<dyn Material + Send + Sync>,
     vertices: [Vector3<f64>; 3],
     normals: Option<[Vector3<f64>; 3]>,
+    texcoords: Option<[Vector2<f64>; 3]>,
     face_normal: Vector3<f64>,
-} //没有实现纹理贴图所以texcoords先不写了
+    tangent: Vector3<f64>,
+    edges: [Vector3<f64>; 2], //v1 - v0和v2 - v0，求交时直接用
+}
 
 impl Triangle {
     pub fn new(
         material: Arc<dyn Material + Send + Sync>,
         vertices: [Vector3<f64>; 3],
         normals: Option<[Vector3<f64>; 3]>,
+        texcoords: Option<[Vector2<f64>; 3]>,
     ) -> Self {
-        let face_normal: Vector3<f64> = (vertices[1] - vertices[0])
-            .cross(vertices[2] - vertices[0])
+        let e1 = vertices[1] - vertices[0];
+        let e2 = vertices[2] - vertices[0];
+        let face_normal: Vector3<f64> = e1.cross(e2).normalize();
+        // 由纹理坐标的偏导解出dp/du，没有纹理坐标或者退化时u就是beta
+        let tangent = texcoords
+            .and_then(|uv| {
+                let duv1 = uv[1] - uv[0];
+                let duv2 = uv[2] - uv[0];
+                let det = duv1.x() * duv2.y() - duv2.x() * duv1.y();
+                if det.abs() < 1e-12 {
+                    None
+                } else {
+                    Some((e1 * duv2.y() - e2 * duv1.y()) / det)
+                }
+            })
+            .unwrap_or(e1)
             .normalize();
         Self {
             material,
             vertices,
             normals,
+            texcoords,
             face_normal,
+            tangent,
+            edges: [e1, e2],
         }
     }
+    pub fn emitter(&self) -> Emitter {
+        Emitter::Triangle(self.vertices)
+    }
 }
 
 impl Object3d for Triangle {
     fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
-        let mat = Matrix3x3::from_array_of_vectors([
-            *ray.get_direction(),
-            self.vertices[0] - self.vertices[1],
-            self.vertices[0] - self.vertices[2],
-        ])
-        .transpose();
-        if mat.det() == 0. {
+        // Möller–Trumbore，用克拉默法则解o + td = v0 + beta * e1 + gamma * e2
+        let [e1, e2] = self.edges;
+        let d = *ray.get_direction();
+        let p: Vector3<f64> = d.cross(e2);
+        let det = e1.dot(p);
+        if det == 0. {
             //没有奇异性判定，用det为0来判定
             None
         } else {
-            let x = mat.inv().dot(self.vertices[0] - *ray.get_origin());
-            // 这里它不能知道x的长度是3，所以没办法用tuple赋值，有点不爽
-            let t = x[0];
-            let beta = x[1];
-            let gamma = x[2];
+            let inv_det = 1. / det;
+            let s = *ray.get_origin() - self.vertices[0];
+            let beta = s.dot(p) * inv_det;
+            let q: Vector3<f64> = s.cross(e1);
+            let gamma = d.dot(q) * inv_det;
+            let t = e2.dot(q) * inv_det;
             if t > tmin && 0. <= beta && 0. <= gamma && beta + gamma <= 1. {
-                let point = ray.point_at_param(t);
-                let w0: Vector3<f64> = (self.vertices[1] - point).cross(self.vertices[2] - point);
-                let w1: Vector3<f64> = (self.vertices[2] - point).cross(self.vertices[0] - point);
-                let w2: Vector3<f64> = (self.vertices[0] - point).cross(self.vertices[1] - point);
-                let w0 = self.face_normal.dot(w0);
-                let w1 = self.face_normal.dot(w1);
-                let w2 = self.face_normal.dot(w2);
+                let alpha = 1. - beta - gamma;
                 //重心坐标插值
                 let mut norm = if let Some(normals) = self.normals {
-                    normals[0] * w0 + normals[1] * w1 + normals[2] * w2
+                    normals[0] * alpha + normals[1] * beta + normals[2] * gamma
                 } else {
                     self.face_normal
                 }
@@ -183,36 +402,59 @@ impl Object3d for Triangle {
                     norm = -norm;
                 }
                 assert!(norm.dot(*ray.get_direction()) <= 0.);
-                Some(Hit::new(t, self.material.clone(), norm))
+                let flipped = self.face_normal.dot(*ray.get_direction()) > 0.;
+                let geometric_normal = if flipped {
+                    -self.face_normal
+                } else {
+                    self.face_normal
+                };
+                // 没有纹理坐标的时候直接用重心坐标
+                let texcoord = if let Some(texcoords) = self.texcoords {
+                    texcoords[0] * alpha + texcoords[1] * beta + texcoords[2] * gamma
+                } else {
+                    Vector2::<f64>::from([beta, gamma])
+                };
+                let mut hit = Hit::new(
+                    t,
+                    self.material.clone(),
+                    geometric_normal,
+                    texcoord,
+                    self.tangent,
+                );
+                hit.set_normal(norm);
+                hit.set_flipped(flipped);
+                Some(hit)
             } else {
                 None
             }
         }
     }
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::from_points(&self.vertices)
+    }
+    fn area_lights(&self) -> Vec<AreaLight> {
+        emissive_light(&self.material, vec![self.emitter()])
+    }
 }
 
 pub struct Transform {
     object: Arc<dyn Object3d + Send + Sync>, //变形前的对象
     transform: Matrix4x4<f64>,
+    forward: Matrix4x4<f64>, //切向量要用正变换
 }
 
 impl Transform {
     pub fn new(object: Arc<dyn Object3d + Send + Sync>, transform: Matrix4x4<f64>) -> Self {
+        let forward = transform;
         let transform = transform.inv();
-        Self { object, transform }
+        Self {
+            object,
+            transform,
+            forward,
+        }
     }
 }
 
-fn transform_point(mat: &Matrix4x4<f64>, point: &Vector3<f64>) -> Vector3<f64> {
-    let point = mat.dot(Vector4::<f64>::from([point.x(), point.y(), point.z(), 1.]));
-    Vector3::<f64>::from([point.x(), point.y(), point.z()])
-}
-
-fn transform_direction(mat: &Matrix4x4<f64>, dir: &Vector3<f64>) -> Vector3<f64> {
-    let dir = mat.dot(Vector4::<f64>::from([dir.x(), dir.y(), dir.z(), 0.]));
-    Vector3::<f64>::from([dir.x(), dir.y(), dir.z()])
-}
-
 impl Object3d for Transform {
     fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
         let tr_source = transform_point(&self.transform, ray.get_origin());
@@ -220,11 +462,43 @@ impl Object3d for Transform {
         let tr_ray = Ray::new(tr_source, tr_direction, Some(*ray.get_flux()));
         let ret = self.object.intersect(&tr_ray, tmin);
         ret.map(|h| -> Hit {
-            let normal =
-                transform_direction(&self.transform.transpose(), h.get_normal()).normalize();
-            Hit::new(h.get_t(), h.get_material().clone(), normal)
+            let normal_matrix = self.transform.transpose();
+            let normal = transform_direction(&normal_matrix, h.get_normal()).normalize();
+            let geometric_normal =
+                transform_direction(&normal_matrix, h.get_geometric_normal()).normalize();
+            let tangent = transform_direction(&self.forward, h.get_tangent()).normalize();
+            let mut hit = Hit::new(
+                h.get_t(),
+                h.get_material().clone(),
+                geometric_normal,
+                *h.get_texcoord(),
+                tangent,
+            );
+            hit.set_normal(normal);
+            hit.set_flipped(h.is_flipped());
+            hit
         })
     }
+    fn bounding_box(&self) -> BoundingBox {
+        // 变换后的包围盒取原包围盒八个角变换之后的包围盒
+        let bbox = self.object.bounding_box();
+        if !bbox.is_finite() {
+            return BoundingBox::infinite();
+        }
+        let corners: Vec<Vector3<f64>> = bbox
+            .corners()
+            .iter()
+            .map(|p| transform_point(&self.forward, p))
+            .collect();
+        BoundingBox::from_points(&corners)
+    }
+    fn area_lights(&self) -> Vec<AreaLight> {
+        self.object
+            .area_lights()
+            .iter()
+            .map(|light| light.transform(&self.forward))
+            .collect()
+    }
 }
 
 pub fn build_group(
@@ -235,6 +509,7 @@ pub fn build_group(
     for object in group_attr.members() {
         group.add_object(build_object3d(object, materials));
     }
+    group.build_bvh();
     Arc::new(group)
 }
 
@@ -245,9 +520,26 @@ pub fn build_plane(
     let material_index = plane_attr["MaterialIndex"].as_usize().unwrap();
     let normal = parse_vector(&plane_attr["Normal"]);
     let d = plane_attr["Offset"].as_f64().unwrap();
+    assert_not_emissive(&materials[material_index], "Plane");
     Arc::new(Plane::new(materials[material_index].clone(), normal, d))
 }
 
+pub fn build_disk(
+    disk_attr: &JsonValue,
+    materials: &[Arc<dyn Material + Send + Sync>],
+) -> Arc<Disk> {
+    let material_index = disk_attr["MaterialIndex"].as_usize().unwrap();
+    let center = parse_vector(&disk_attr["Center"]);
+    let normal = parse_vector(&disk_attr["Normal"]);
+    let radius = disk_attr["Radius"].as_f64().unwrap();
+    Arc::new(Disk::new(
+        materials[material_index].clone(),
+        center,
+        normal,
+        radius,
+    ))
+}
+
 pub fn build_triangle(
     triangle_attr: &JsonValue,
     materials: &[Arc<dyn Material + Send + Sync>],
@@ -269,10 +561,21 @@ pub fn build_triangle(
         } else {
             None
         };
+    let texcoords: Option<[Vector2<f64>; 3]> =
+        if let JsonValue::Array(texcoords) = &triangle_attr["TexCoords"] {
+            Some([
+                parse_vector2(&texcoords[0]),
+                parse_vector2(&texcoords[1]),
+                parse_vector2(&texcoords[2]),
+            ])
+        } else {
+            None
+        };
     Arc::new(Triangle::new(
         materials[material_index].clone(),
         vertices,
         normals,
+        texcoords,
     ))
 }
 
@@ -343,12 +646,14 @@ pub fn build_object3d(
 ) -> Arc<dyn Object3d + Send + Sync> {
     let object_type = object_attr["Type"].as_str().unwrap();
     match object_type {
-        "Group" => build_group(object_attr, materials),
+        "Group" => build_group(&object_attr["Objects"], materials),
         "Plane" => build_plane(object_attr, materials),
+        "Disk" => build_disk(object_attr, materials),
         "Triangle" => build_triangle(object_attr, materials),
         "Sphere" => build_sphere(object_attr, materials),
         "Transform" => build_transform(object_attr, materials),
         "Mesh" => build_mesh(object_attr, materials),
+        "RevSurface" => build_revsurface(object_attr, materials),
         _ => panic!("Wrong object type"),
     }
 }
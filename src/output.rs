@@ -0,0 +1,124 @@
+use crate::tonemap::ToneMapper;
+use image::{codecs::hdr::HdrEncoder, ImageBuffer, ImageResult, Rgb};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+use vecmat::vector::Vector3;
+
+// pixels按图片的顺序存，第一行是最上面一行，值是线性的辐射亮度
+// 按扩展名选格式：exr、hdr、pfm保存浮点数，其他格式色调映射之后交给image库存成8位图片
+pub fn save_image(
+    pixels: &[Vector3<f64>],
+    width: usize,
+    height: usize,
+    output_file: &str,
+    tone_mapper: &ToneMapper,
+) -> ImageResult<()> {
+    let extension = Path::new(output_file)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("exr") => write_exr(pixels, width, height, output_file)?,
+        Some("hdr") => {
+            let data: Vec<Rgb<f32>> = pixels
+                .iter()
+                .map(|p| Rgb([p.x() as f32, p.y() as f32, p.z() as f32]))
+                .collect();
+            let writer = BufWriter::new(File::create(output_file)?);
+            HdrEncoder::new(writer).encode(&data, width, height)?;
+        }
+        Some("pfm") => write_pfm(pixels, width, height, output_file)?,
+        _ => {
+            ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+                Rgb(tone_mapper.encode(&pixels[y as usize * width + x as usize]))
+            })
+            .save(output_file)?;
+        }
+    }
+    Ok(())
+}
+
+// 不压缩的单层OpenEXR，通道是32位浮点数
+fn write_exr(
+    pixels: &[Vector3<f64>],
+    width: usize,
+    height: usize,
+    output_file: &str,
+) -> io::Result<()> {
+    let mut header: Vec<u8> = Vec::new();
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+    // 通道要按名字排序
+    let mut channels: Vec<u8> = Vec::new();
+    for name in ["B", "G", "R"].iter() {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&2_i32.to_le_bytes()); //FLOAT
+        channels.extend_from_slice(&[0, 0, 0, 0]); //pLinear和保留字节
+        channels.extend_from_slice(&1_i32.to_le_bytes());
+        channels.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    channels.push(0);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect();
+    attribute("channels", "chlist", &channels);
+    attribute("compression", "compression", &[0]);
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]);
+    attribute("pixelAspectRatio", "float", &1_f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1_f32.to_le_bytes());
+    header.push(0);
+
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    writer.write_all(&[0x76, 0x2f, 0x31, 0x01])?;
+    writer.write_all(&2_u32.to_le_bytes())?;
+    writer.write_all(&header)?;
+    // 每行一块，先写每块在文件里的偏移
+    let line_size = 8 + width * 3 * 4;
+    let first_line = 8 + header.len() + height * 8;
+    for y in 0..height {
+        writer.write_all(&((first_line + y * line_size) as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&((width * 3 * 4) as i32).to_le_bytes())?;
+        let line = &pixels[y * width..(y + 1) * width];
+        for channel in [2, 1, 0].iter() {
+            for p in line {
+                writer.write_all(&(p[*channel] as f32).to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+// PFM从最下面一行开始存，比例因子为负表示小端序
+fn write_pfm(
+    pixels: &[Vector3<f64>],
+    width: usize,
+    height: usize,
+    output_file: &str,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for p in &pixels[y * width..(y + 1) * width] {
+            for value in p.iter() {
+                writer.write_all(&(*value as f32).to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
@@ -1,206 +1,457 @@
-use crate::utils::{get_dist, get_max, get_min};
+use crate::{
+    hashgrid::HashGrid,
+    kernel::Kernel,
+    settings::RenderSettings,
+    spectrum::{bin, tint, to_xyz, BINS},
+};
 use adqselect::nth_element;
 use core::f64;
-use lazy_static::lazy_static;
-use std::cmp::Ordering;
+use rand::{thread_rng, Rng};
+use std::{sync::Arc, thread};
 use vecmat::{traits::Dot, vector::Vector3};
+// 为了省内存压缩存放：位置用f32，方向用八面体映射压成两个16位整数，
+// 通量的三个通道共用一个指数，波长为0表示RGB模式
 #[derive(Clone, Copy)]
 pub struct Photon {
-    pub pos: Vector3<f64>,
-    pub dir: Vector3<f64>,
-    pub norm: Vector3<f64>,
-    pub flux: Vector3<f64>,
+    pos: [f32; 3],
+    dir: u32,
+    flux: u32,
+    wavelength: f32,
 }
 
 impl Photon {
     pub fn new(
         pos: Vector3<f64>,
         dir: Vector3<f64>,
-        norm: Vector3<f64>,
         flux: Vector3<f64>,
+        wavelength: Option<f64>,
     ) -> Self {
         Self {
-            pos,
-            dir,
-            norm,
-            flux,
+            pos: [pos.x() as f32, pos.y() as f32, pos.z() as f32],
+            dir: encode_direction(&dir),
+            flux: encode_flux(&flux),
+            wavelength: wavelength.unwrap_or(0.) as f32,
         }
     }
+    pub fn pos(&self) -> Vector3<f64> {
+        Vector3::<f64>::from([self.pos[0] as f64, self.pos[1] as f64, self.pos[2] as f64])
+    }
+    // 单位向量
+    pub fn dir(&self) -> Vector3<f64> {
+        decode_direction(self.dir)
+    }
+    pub fn flux(&self) -> Vector3<f64> {
+        decode_flux(self.flux)
+    }
+    pub fn wavelength(&self) -> Option<f64> {
+        if self.wavelength > 0. {
+            Some(self.wavelength as f64)
+        } else {
+            None
+        }
+    }
+}
+
+// 八面体映射：单位球投影到|x| + |y| + |z| = 1上，下半部分翻折到外面的四个角，
+// 再把[-1, 1]的两个坐标各量化成16位
+fn encode_direction(dir: &Vector3<f64>) -> u32 {
+    let l1 = dir.x().abs() + dir.y().abs() + dir.z().abs();
+    if l1 == 0. {
+        return 0;
+    }
+    let (mut u, mut v) = (dir.x() / l1, dir.y() / l1);
+    if dir.z() < 0. {
+        let (pu, pv) = (u, v);
+        u = (1. - pv.abs()) * pu.signum();
+        v = (1. - pu.abs()) * pv.signum();
+    }
+    let quantize = |x: f64| ((x.clamp(-1., 1.) * 0.5 + 0.5) * 65535.).round() as u32;
+    quantize(u) | quantize(v) << 16
 }
 
-pub static ALPHA: f64 = 0.7;
+fn decode_direction(code: u32) -> Vector3<f64> {
+    let dequantize = |q: u32| (q & 0xffff) as f64 / 65535. * 2. - 1.;
+    let (mut u, mut v) = (dequantize(code), dequantize(code >> 16));
+    let z = 1. - u.abs() - v.abs();
+    if z < 0. {
+        let (pu, pv) = (u, v);
+        u = (1. - pv.abs()) * pu.signum();
+        v = (1. - pu.abs()) * pv.signum();
+    }
+    Vector3::<f64>::from([u, v, z]).normalize()
+}
+
+// 和Radiance的RGBE一样是三个8位尾数加一个8位指数，尾数随机舍入，解码后的期望不变；
+// 指数取得让最大的通道缩放后不超过255，随机舍入以后也不会进位到256
+fn encode_flux(flux: &Vector3<f64>) -> u32 {
+    let flux = flux.map(|x| x.max(0.));
+    let max = flux.max();
+    if !max.is_finite() || max <= f64::powi(2., -120) {
+        return 0;
+    }
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max * f64::powi(2., 8 - exponent) > 255. {
+        exponent += 1;
+    }
+    assert!(exponent < 128, "Photon flux {} is too large to encode", max);
+    let scale = f64::powi(2., 8 - exponent);
+    let mut rng = thread_rng();
+    let mantissa = flux.map(|x| (x * scale + rng.gen_range(0.0..1.0)).floor());
+    mantissa.x() as u32
+        | (mantissa.y() as u32) << 8
+        | (mantissa.z() as u32) << 16
+        | ((exponent + 128) as u32) << 24
+}
+
+fn decode_flux(code: u32) -> Vector3<f64> {
+    if code == 0 {
+        return Vector3::<f64>::fill(0.);
+    }
+    let scale = f64::powi(2., (code >> 24) as i32 - 128 - 8);
+    Vector3::<f64>::from([
+        (code & 0xff) as f64,
+        (code >> 8 & 0xff) as f64,
+        (code >> 16 & 0xff) as f64,
+    ]) * scale
+}
 
 #[derive(Clone, Copy)]
 pub struct HitPoint {
     pub radius: f64,
     pub n: f64,
     pub tau: Vector3<f64>,
-    pub pos: Option<Vector3<f64>>,
+    pub direct: Vector3<f64>, //相机光线直接看到的自发光，各轮累加
 }
 
 impl HitPoint {
-    pub fn new() -> Self {
-        let radius = 0.5;
+    pub fn new(radius: f64) -> Self {
         let n = 0.;
         let tau = Vector3::<f64>::from([0., 0., 0.]);
-        let pos = None;
+        let direct = Vector3::<f64>::from([0., 0., 0.]);
         Self {
             radius,
             n,
             tau,
-            pos,
+            direct,
+        }
+    }
+    // 把这一轮收集到的统计量并进来，按SPPM的规则缩小半径
+    pub fn update(&mut self, buffer: &HitPoint, first_round: bool, alpha: f64) {
+        self.direct += buffer.direct;
+        if first_round {
+            self.n = buffer.n;
+            self.tau = buffer.tau;
+        } else if self.n + buffer.n > 0. {
+            let ratio = (self.n + alpha * buffer.n) / (self.n + buffer.n);
+            self.radius *= f64::sqrt(ratio);
+            self.tau = (self.tau + buffer.tau) * ratio;
+            self.n += buffer.n * ratio;
         }
     }
 }
 
-pub struct Node {
-    min_pos: Vector3<f64>,
-    max_pos: Vector3<f64>,
-    lchild: Option<Box<Node>>,
-    rchild: Option<Box<Node>>,
-    photon_index: usize,
+// 相机光线最后落在漫反射面上的点，光子在这里被收集
+#[derive(Clone, Copy)]
+pub struct VisiblePoint {
+    pub pos: Vector3<f64>,
+    pub color: Vector3<f64>,
+    pub normal: Vector3<f64>,
+    pub geometric_normal: Vector3<f64>,
+    pub scale: Vector3<f64>,     //相机光线走到这里时的通量
+    pub wavelength: Option<f64>, //光谱模式下这条相机光线的波长
 }
 
-impl Node {
-    fn new(
-        min_pos: Vector3<f64>,
-        max_pos: Vector3<f64>,
-        lchild: Option<Box<Node>>,
-        rchild: Option<Box<Node>>,
-        photon_index: usize,
-    ) -> Self {
-        Self {
-            min_pos,
-            max_pos,
-            lchild,
-            rchild,
-            photon_index,
+impl VisiblePoint {
+    // 半径为radius时光子按核函数加权后对这个点的贡献，不算在收集范围里的光子返回None
+    pub fn contribution(
+        &self,
+        radius: f64,
+        kernel: &Kernel,
+        point: &Photon,
+    ) -> Option<Vector3<f64>> {
+        // 光谱模式下只收和相机光线在同一个波段里的光子，权重乘上波段数
+        let matched = match (self.wavelength, point.wavelength()) {
+            (Some(camera), Some(photon)) => bin(camera) == bin(photon),
+            _ => true,
+        };
+        if !matched {
+            return None;
+        }
+        let weight = kernel.weight((point.pos() - self.pos).square_length(), radius)?;
+        // 只收几何表面正面来的光子，防止漏光
+        let dir = point.dir();
+        let cos_geometric = self.geometric_normal.dot(dir);
+        if cos_geometric >= 0. {
+            return Some(Vector3::<f64>::fill(0.));
         }
+        // 着色法向和几何法向不一致时，按伴随BSDF修正光子的权重
+        let correction = self.normal.dot(dir).abs() / -cos_geometric;
+        let value =
+            tint(point.wavelength(), &self.color) * point.flux() * self.scale * correction * weight
+                / f64::consts::PI;
+        Some(match point.wavelength() {
+            Some(lambda) => to_xyz(lambda, value.x() * BINS as f64),
+            None => value,
+        })
     }
 }
 
-struct PhotonCompare {
-    dimension: usize,
-}
-
-impl PhotonCompare {
-    fn new(dimension: usize) -> Self {
-        Self { dimension }
-    }
-    fn compare(&self, a: &Photon, b: &Photon) -> Ordering {
-        match self.dimension {
-            0 => a.pos.x().partial_cmp(&b.pos.x()).unwrap(),
-            1 => a.pos.y().partial_cmp(&b.pos.y()).unwrap(),
-            2 => a.pos.z().partial_cmp(&b.pos.z()).unwrap(),
-            _ => panic!("PhotonCompare dimension wrong!"),
-        }
+// 光子图的公共接口，不同的加速结构只要给出查询球附近的候选光子
+pub trait PhotonMap {
+    // 对可能落在pos周围radius以内的光子调用f，距离由调用者自己判断
+    fn for_each_candidate(&self, pos: &Vector3<f64>, radius: f64, f: &mut dyn FnMut(&Photon));
+    // 在可见点周围收集光子，累加到hitpoint里
+    fn search(&self, hitpoint: &mut HitPoint, point: &VisiblePoint, kernel: &Kernel) {
+        let radius = hitpoint.radius;
+        self.for_each_candidate(&point.pos, radius, &mut |photon| {
+            if let Some(value) = point.contribution(radius, kernel, photon) {
+                hitpoint.n += 1.;
+                hitpoint.tau += value;
+            }
+        })
     }
 }
 
-lazy_static! {
-    static ref COMPS: [PhotonCompare; 3] = [
-        PhotonCompare::new(0),
-        PhotonCompare::new(1),
-        PhotonCompare::new(2)
-    ];
+// n个结点的左平衡kd树里左子树的结点数，这样的树是完全二叉树，可以按堆的顺序存
+fn left_size(n: usize) -> usize {
+    if n <= 1 {
+        return 0;
+    }
+    let height = (usize::BITS - 1 - n.leading_zeros()) as usize; //除了最后一层以外的层数
+    let full = (1 << height) - 1;
+    let last = n - full;
+    (full - 1) / 2 + last.min(1 << (height - 1))
 }
 
+// 隐式的左平衡kd树：按堆的顺序存在一个数组里，第i个结点的孩子是2i + 1和2i + 2，
+// 每个结点只多存一个划分的维度，不用单独分配结点和包围盒
 pub struct KDTree {
-    root: Option<Box<Node>>,
-    map: Vec<Photon>, //从主函数拿到所有权就可以了，后面不会再用
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
 }
 
 impl KDTree {
-    pub fn new(mut map: Vec<Photon>) -> Self {
-        let mut root: Option<Box<Node>> = None;
-        let len = map.len();
-        Self::build(&mut root, &mut map, 0, len, 0);
-        Self { root, map }
-    }
-    fn build(
-        root: &mut Option<Box<Node>>,
-        map: &mut Vec<Photon>,
-        left: usize,
-        right: usize,
-        dep: usize,
-    ) {
-        if root.is_none() {
-            // println!("kdtree build: left is {} and right is {}", &left, &right);
-            let mid = (left + right) / 2;
-            let relative_mid = (right - left) / 2;
-            nth_element(&mut map[left..right], relative_mid, &mut |x, y| {
-                (*COMPS)[dep % 3].compare(x, y)
-            });
-            // println!("partition complete: left is {} and right is {}", &left, &right);
-            *root = Some(Box::new(Node::new(
-                map[mid].pos,
-                map[mid].pos,
-                None,
-                None,
-                mid,
-            )));
-            if left < mid {
-                if let Some(root) = root {
-                    Self::build(&mut root.lchild, map, left, mid, dep + 1);
-                    if let Some(lchild) = &root.lchild {
-                        root.min_pos = get_min(&root.min_pos, &lchild.min_pos);
-                        root.max_pos = get_max(&root.max_pos, &lchild.max_pos);
-                    }
-                }
+    // threads是建树时最多同时用的线程数
+    pub fn new(mut map: Vec<Photon>, threads: usize) -> Self {
+        let (photons, axes) = Self::build(&mut map, threads);
+        Self { photons, axes }
+    }
+    // 在包围盒最长的维度上，把左平衡的位置上的光子选出来，返回它的下标和维度
+    fn split(map: &mut [Photon]) -> (usize, usize) {
+        let mut min_pos = map[0].pos;
+        let mut max_pos = map[0].pos;
+        for photon in map.iter() {
+            for axis in 0..3 {
+                min_pos[axis] = min_pos[axis].min(photon.pos[axis]);
+                max_pos[axis] = max_pos[axis].max(photon.pos[axis]);
             }
-            if mid + 1 < right {
-                if let Some(root) = root {
-                    Self::build(&mut root.rchild, map, mid + 1, right, dep + 1);
-                    if let Some(rchild) = &root.rchild {
-                        root.min_pos = get_min(&root.min_pos, &rchild.min_pos);
-                        root.max_pos = get_max(&root.max_pos, &rchild.max_pos);
-                    }
-                }
+        }
+        let axis = (0..3)
+            .max_by(|&a, &b| {
+                (max_pos[a] - min_pos[a])
+                    .partial_cmp(&(max_pos[b] - min_pos[b]))
+                    .unwrap()
+            })
+            .unwrap();
+        let mid = left_size(map.len());
+        nth_element(map, mid, &mut |x, y| {
+            x.pos[axis].partial_cmp(&y.pos[axis]).unwrap()
+        });
+        (mid, axis)
+    }
+    // 以map为根的子树，返回按堆的顺序排好的光子和划分维度
+    fn build(map: &mut [Photon], threads: usize) -> (Vec<Photon>, Vec<u8>) {
+        let n = map.len();
+        if threads <= 1 || n < 2 {
+            let mut photons = map.to_vec();
+            let mut axes = vec![0; n];
+            Self::fill(map, &mut photons, &mut axes, 0);
+            return (photons, axes);
+        }
+        let (mid, axis) = Self::split(map);
+        let median = map[mid];
+        let (left, rest) = map.split_at_mut(mid);
+        let right = &mut rest[1..];
+        // 左右子树的光子不相交，分给两个线程去建，建好再交错地拷到整棵树的位置上
+        let subtrees = thread::scope(|s| {
+            let handle = s.spawn(|| Self::build(left, threads / 2));
+            let rchild = Self::build(right, threads - threads / 2);
+            [(1, handle.join().unwrap()), (2, rchild)]
+        });
+        let mut photons = vec![median; n];
+        let mut axes = vec![0; n];
+        axes[0] = axis as u8;
+        for (root, (sub_photons, sub_axes)) in subtrees {
+            for (k, (photon, axis)) in sub_photons.into_iter().zip(sub_axes).enumerate() {
+                // 子树里第depth层的第offset个结点
+                let depth = usize::BITS - 1 - (k + 1).leading_zeros();
+                let offset = k + 1 - (1 << depth);
+                let index = ((root + 1) << depth) - 1 + offset;
+                photons[index] = photon;
+                axes[index] = axis;
             }
         }
+        (photons, axes)
     }
-    fn intersect(p: &Node, hitpoint: &HitPoint) -> bool {
-        hitpoint.radius
-            >= f64::sqrt(
-                get_dist(p.min_pos.x(), p.max_pos.x(), hitpoint.pos.unwrap().x())
-                    * get_dist(p.min_pos.x(), p.max_pos.x(), hitpoint.pos.unwrap().x())
-                    + get_dist(p.min_pos.y(), p.max_pos.y(), hitpoint.pos.unwrap().y())
-                        * get_dist(p.min_pos.y(), p.max_pos.y(), hitpoint.pos.unwrap().y())
-                    + get_dist(p.min_pos.z(), p.max_pos.z(), hitpoint.pos.unwrap().z())
-                        * get_dist(p.min_pos.z(), p.max_pos.z(), hitpoint.pos.unwrap().z()),
-            )
+    fn fill(map: &mut [Photon], photons: &mut [Photon], axes: &mut [u8], index: usize) {
+        if map.is_empty() {
+            return;
+        }
+        let (mid, axis) = Self::split(map);
+        photons[index] = map[mid];
+        axes[index] = axis as u8;
+        let (left, rest) = map.split_at_mut(mid);
+        Self::fill(left, photons, axes, 2 * index + 1);
+        Self::fill(&mut rest[1..], photons, axes, 2 * index + 2);
     }
-    pub fn query(
-        &self,
-        p: &Option<Box<Node>>,
-        hitpoint: &mut HitPoint,
-        color: &Vector3<f64>,
-        normal: &Vector3<f64>,
-        scale: &Vector3<f64>,
-    ) {
-        let hit_pos = hitpoint.pos.unwrap();
-        if let Some(p) = p {
-            if Self::intersect(p, hitpoint) {
-                let point = &self.map[p.photon_index];
-                let dist = point.pos - hit_pos;
-                if dist.square_length() <= hitpoint.radius {
-                    hitpoint.n += 1.;
-                    if normal.dot(point.dir) < 0. {
-                        hitpoint.tau += *color * point.flux * *scale / f64::consts::PI
-                    }
-                }
-                self.query(&p.lchild, hitpoint, color, normal, scale);
-                self.query(&p.rchild, hitpoint, color, normal, scale);
+    // 先走查询点所在的一侧，到划分平面的距离不超过radius时才看另一侧
+    fn visit(&self, index: usize, pos: &Vector3<f64>, radius: f64, f: &mut dyn FnMut(&Photon)) {
+        if index >= self.photons.len() {
+            return;
+        }
+        let photon = &self.photons[index];
+        let axis = self.axes[index] as usize;
+        let delta = pos[axis] - photon.pos[axis] as f64;
+        let (near, far) = if delta < 0. {
+            (2 * index + 1, 2 * index + 2)
+        } else {
+            (2 * index + 2, 2 * index + 1)
+        };
+        self.visit(near, pos, radius, f);
+        if delta.abs() <= radius {
+            f(photon);
+            self.visit(far, pos, radius, f);
+        }
+    }
+}
+
+impl PhotonMap for KDTree {
+    fn for_each_candidate(&self, pos: &Vector3<f64>, radius: f64, f: &mut dyn FnMut(&Photon)) {
+        self.visit(0, pos, radius, f)
+    }
+}
+
+// 光子图用的加速结构
+#[derive(Clone, Copy)]
+pub enum PhotonMapType {
+    KDTree,
+    HashGrid,
+}
+
+impl PhotonMapType {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "KDTree" => Self::KDTree,
+            "HashGrid" => Self::HashGrid,
+            _ => panic!("Unknown photon map {}", name),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::KDTree => "KDTree",
+            Self::HashGrid => "HashGrid",
+        }
+    }
+}
+
+// radius是这一轮最大的查询半径，网格的格子按它来定大小
+pub fn build_photon_map(
+    map: Vec<Photon>,
+    radius: f64,
+    settings: &RenderSettings,
+) -> Arc<dyn PhotonMap + Send + Sync> {
+    match settings.photon_map {
+        PhotonMapType::KDTree => Arc::new(KDTree::new(map, settings.parallel_number)),
+        PhotonMapType::HashGrid => Arc::new(HashGrid::new(map, radius)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direction_round_trip() {
+        let directions = [
+            [1., 0., 0.],
+            [0., -1., 0.],
+            [0., 0., 1.],
+            [0., 0., -1.],
+            [0.3, -0.4, 0.5],
+            [-0.7, 0.2, -0.6],
+            [1e-3, 1., -1e-3],
+        ];
+        for dir in directions.iter() {
+            let dir = Vector3::<f64>::from(*dir).normalize();
+            let decoded = decode_direction(encode_direction(&dir));
+            assert!(
+                (decoded - dir).length() < 1e-4,
+                "{:?} -> {:?}",
+                dir,
+                decoded
+            );
+        }
+    }
+
+    #[test]
+    fn flux_round_trip() {
+        let fluxes = [
+            [1., 0.5, 0.001],
+            [255.5 / 128., 1., 0.], //最大的通道缩放后在255和256之间
+            [3e-5, 7e-5, 2e-5],
+            [1e6, 1.5e6, 2e6],
+        ];
+        let draws = 100000;
+        for flux in fluxes.iter() {
+            let flux = Vector3::<f64>::from(*flux);
+            // 舍入的误差不超过一个量化步长，平均下来收敛到原来的值
+            let step = f64::powi(2., flux.max().log2().floor() as i32 - 6);
+            let mut sum = Vector3::<f64>::fill(0.);
+            for _ in 0..draws {
+                let decoded = decode_flux(encode_flux(&flux));
+                assert!((decoded - flux).map(f64::abs).max() < step, "{:?}", decoded);
+                sum += decoded;
             }
+            let error = (sum / draws as f64 - flux).map(f64::abs).max();
+            assert!(
+                error < step * 0.01,
+                "{:?} averages to {:?}",
+                flux,
+                sum / draws as f64
+            );
         }
     }
-    pub fn search(
-        &self,
-        hitpoint: &mut HitPoint,
-        color: &Vector3<f64>,
-        normal: &Vector3<f64>,
-        scale: &Vector3<f64>,
-    ) {
-        self.query(&self.root, hitpoint, color, normal, scale)
+
+    #[test]
+    fn flux_zero() {
+        for flux in [
+            [0., 0., 0.],
+            [-1., -2., 0.],
+            [f64::INFINITY, 1., 1.],
+            [1e-40, 0., 0.],
+        ]
+        .iter()
+        {
+            assert_eq!(encode_flux(&Vector3::<f64>::from(*flux)), 0);
+        }
+        assert_eq!(decode_flux(0), Vector3::<f64>::fill(0.));
+    }
+
+    #[test]
+    fn left_size_matches_heap() {
+        // 按堆的顺序数左子树里编号小于n的结点
+        let count = |n: usize| {
+            let mut size = 0;
+            let (mut begin, mut width) = (1, 1);
+            while begin < n {
+                size += (n - begin).min(width);
+                begin = begin * 2 + 1;
+                width *= 2;
+            }
+            size
+        };
+        for n in 0..1000 {
+            assert_eq!(left_size(n), count(n), "n = {}", n);
+        }
     }
 }
@@ -3,6 +3,8 @@ pub struct Ray {
     origin: Vector3<f64>,
     direction: Vector3<f64>,
     color: Vector3<f64>,
+    channel: Option<usize>,  //经过色散材质之后只带这一个通道的通量
+    wavelength: Option<f64>, //光谱模式下光线的波长，通量的三个分量都是这个波长上的值
 }
 
 impl Ray {
@@ -15,6 +17,8 @@ impl Ray {
                 Some(color) => color,
                 None => Vector3::<f64>::from([1., 1., 1.]),
             },
+            channel: None,
+            wavelength: None,
         }
     }
     pub fn get_origin(&self) -> &Vector3<f64> {
@@ -26,6 +30,18 @@ impl Ray {
     pub fn get_flux(&self) -> &Vector3<f64> {
         &self.color
     }
+    pub fn get_channel(&self) -> Option<usize> {
+        self.channel
+    }
+    pub fn set_channel(&mut self, channel: usize) {
+        self.channel = Some(channel);
+    }
+    pub fn get_wavelength(&self) -> Option<f64> {
+        self.wavelength
+    }
+    pub fn set_wavelength(&mut self, wavelength: f64) {
+        self.wavelength = Some(wavelength);
+    }
     pub fn set_color(&mut self, color: Vector3<f64>) {
         self.color = color;
     }
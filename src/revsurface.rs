@@ -0,0 +1,257 @@
+use crate::{
+    bvh::BoundingBox,
+    curve::{build_curve, Curve},
+    hit::Hit,
+    materials::Material,
+    object3d::{assert_not_emissive, Object3d},
+    ray::Ray,
+};
+use core::f64;
+use json::JsonValue;
+use std::sync::Arc;
+use vecmat::vector::{Vector2, Vector3};
+
+const NEWTON_ITERATIONS: usize = 20;
+const NEWTON_EPS: f64 = 1e-9;
+
+// 曲线绕y轴旋转得到的曲面，曲面上的点是(x(t)cosθ, y(t), x(t)sinθ)
+// 曲线按y增大的方向给出时法向朝外
+pub struct RevSurface {
+    material: Arc<dyn Material + Send + Sync>,
+    curve: Arc<dyn Curve + Send + Sync>,
+    radius: f64,
+    y_min: f64,
+    y_max: f64,
+    samples: usize,
+}
+
+impl RevSurface {
+    pub fn new(
+        material: Arc<dyn Material + Send + Sync>,
+        curve: Arc<dyn Curve + Send + Sync>,
+    ) -> Self {
+        // 包围圆柱由控制点的凸包给出
+        let controls = curve.get_controls();
+        let radius = controls.iter().map(|p| p.x().abs()).fold(0., f64::max);
+        let y_min = controls.iter().map(|p| p.y()).fold(f64::INFINITY, f64::min);
+        let y_max = controls
+            .iter()
+            .map(|p| p.y())
+            .fold(f64::NEG_INFINITY, f64::max);
+        let samples = 8 * controls.len();
+        Self {
+            material,
+            curve,
+            radius,
+            y_min,
+            y_max,
+            samples,
+        }
+    }
+    // 光线和包围圆柱的交区间
+    fn bounding_interval(&self, ray: &Ray, tmin: f64) -> Option<(f64, f64)> {
+        let o = ray.get_origin();
+        let d = ray.get_direction();
+        let mut t_near = tmin;
+        let mut t_far = f64::INFINITY;
+        let a = d.x() * d.x() + d.z() * d.z();
+        let b = 2. * (o.x() * d.x() + o.z() * d.z());
+        let c = o.x() * o.x() + o.z() * o.z() - self.radius * self.radius;
+        if a == 0. {
+            if c > 0. {
+                return None;
+            }
+        } else {
+            let delta = b * b - 4. * a * c;
+            if delta < 0. {
+                return None;
+            }
+            let qd = f64::sqrt(delta);
+            t_near = t_near.max((-b - qd) / (2. * a));
+            t_far = t_far.min((-b + qd) / (2. * a));
+        }
+        if d.y() == 0. {
+            if o.y() < self.y_min || o.y() > self.y_max {
+                return None;
+            }
+        } else {
+            let t1 = (self.y_min - o.y()) / d.y();
+            let t2 = (self.y_max - o.y()) / d.y();
+            t_near = t_near.max(t1.min(t2));
+            t_far = t_far.min(t1.max(t2));
+        }
+        if t_near > t_far {
+            None
+        } else {
+            Some((t_near, t_far))
+        }
+    }
+    // 在[lo, hi]内有变号的区间上做牛顿迭代，跑出区间就退回二分
+    fn newton<F>(f: F, mut lo: f64, mut hi: f64) -> Option<f64>
+    where
+        F: Fn(f64) -> (f64, f64),
+    {
+        let (f_lo, _) = f(lo);
+        let (f_hi, _) = f(hi);
+        if f_lo * f_hi > 0. {
+            return None;
+        }
+        let sign_lo = f_lo.signum();
+        let mut t = (lo + hi) / 2.;
+        for _ in 0..NEWTON_ITERATIONS {
+            let (value, derivative) = f(t);
+            if value.abs() < NEWTON_EPS {
+                return Some(t);
+            }
+            if value.signum() == sign_lo {
+                lo = t;
+            } else {
+                hi = t;
+            }
+            let next = if derivative != 0. {
+                t - value / derivative
+            } else {
+                f64::NAN
+            };
+            t = if next > lo && next < hi {
+                next
+            } else {
+                (lo + hi) / 2.
+            };
+        }
+        Some(t)
+    }
+    // 函数在[lo, hi]两端导数异号时，二分找出中间的极值点
+    fn extremum<F>(f: F, mut lo: f64, mut hi: f64) -> f64
+    where
+        F: Fn(f64) -> (f64, f64),
+    {
+        let (_, d_lo) = f(lo);
+        for _ in 0..NEWTON_ITERATIONS {
+            let t = (lo + hi) / 2.;
+            let (_, derivative) = f(t);
+            if derivative.signum() == d_lo.signum() {
+                lo = t;
+            } else {
+                hi = t;
+            }
+        }
+        (lo + hi) / 2.
+    }
+    // 把[0, 1]切成若干段，在每段变号的地方找根
+    // 两端同号但导数异号的段里可能藏着两个根（光线擦过曲面），在极值点处切开再找
+    fn find_roots<F>(&self, f: F) -> Vec<f64>
+    where
+        F: Fn(f64) -> (f64, f64),
+    {
+        let mut roots = Vec::new();
+        let step = 1. / self.samples as f64;
+        for i in 0..self.samples {
+            let lo = i as f64 * step;
+            let hi = lo + step;
+            let (f_lo, d_lo) = f(lo);
+            let (f_hi, d_hi) = f(hi);
+            if f_lo * f_hi > 0. && d_lo * d_hi < 0. {
+                let mid = Self::extremum(&f, lo, hi);
+                roots.extend(Self::newton(&f, lo, mid));
+                roots.extend(Self::newton(&f, mid, hi));
+            } else if let Some(t) = Self::newton(&f, lo, hi) {
+                roots.push(t);
+            }
+        }
+        roots
+    }
+    fn make_hit(&self, ray: &Ray, s: f64, t: f64) -> Hit {
+        let point = ray.point_at_param(s);
+        let theta = f64::atan2(point.z(), point.x());
+        let (_, tangent) = self.curve.evaluate(t);
+        let normal = Vector3::<f64>::from([
+            tangent.y() * f64::cos(theta),
+            -tangent.x(),
+            tangent.y() * f64::sin(theta),
+        ])
+        .normalize();
+        // u绕轴一圈，v沿着曲线
+        let u = 0.5 + theta / (2. * f64::consts::PI);
+        let tangent = Vector3::<f64>::from([-f64::sin(theta), 0., f64::cos(theta)]);
+        Hit::new(
+            s,
+            self.material.clone(),
+            normal,
+            Vector2::<f64>::from([u, t]),
+            tangent,
+        )
+    }
+}
+
+impl Object3d for RevSurface {
+    fn intersect(&self, ray: &Ray, tmin: f64) -> Option<Hit> {
+        let (t_near, t_far) = self.bounding_interval(ray, tmin)?;
+        let o = *ray.get_origin();
+        let d = *ray.get_direction();
+        let eps = 1e-6 * (1. + t_far.abs().min(1e6));
+        let mut best: Option<(f64, f64)> = None;
+        let mut update = |s: f64, t: f64| {
+            if s > tmin && s >= t_near - eps && s <= t_far + eps {
+                if let Some((best_s, _)) = best {
+                    if s >= best_s {
+                        return;
+                    }
+                }
+                best = Some((s, t));
+            }
+        };
+        if d.y().abs() > 1e-9 * d.length() {
+            // 光线高度y(t)处的参数是s(t)，要求那里的点到轴的距离等于x(t)
+            let f = |t: f64| {
+                let (p, dp): (Vector2<f64>, Vector2<f64>) = self.curve.evaluate(t);
+                let s = (p.y() - o.y()) / d.y();
+                let ds = dp.y() / d.y();
+                let px = o.x() + s * d.x();
+                let pz = o.z() + s * d.z();
+                let value = px * px + pz * pz - p.x() * p.x();
+                let derivative = 2. * (px * d.x() + pz * d.z()) * ds - 2. * p.x() * dp.x();
+                (value, derivative)
+            };
+            for t in self.find_roots(f) {
+                let (p, _) = self.curve.evaluate(t);
+                update((p.y() - o.y()) / d.y(), t);
+            }
+        } else {
+            // 水平的光线：先找出高度对应的t，再和那一层的圆求交
+            let f = |t: f64| {
+                let (p, dp) = self.curve.evaluate(t);
+                (p.y() - o.y(), dp.y())
+            };
+            let a = d.x() * d.x() + d.z() * d.z();
+            let b = 2. * (o.x() * d.x() + o.z() * d.z());
+            for t in self.find_roots(f) {
+                let (p, _) = self.curve.evaluate(t);
+                let c = o.x() * o.x() + o.z() * o.z() - p.x() * p.x();
+                let delta = b * b - 4. * a * c;
+                if delta >= 0. {
+                    let qd = f64::sqrt(delta);
+                    update((-b - qd) / (2. * a), t);
+                    update((-b + qd) / (2. * a), t);
+                }
+            }
+        }
+        best.map(|(s, t)| self.make_hit(ray, s, t))
+    }
+    fn bounding_box(&self) -> BoundingBox {
+        BoundingBox::new(
+            Vector3::<f64>::from([-self.radius, self.y_min, -self.radius]),
+            Vector3::<f64>::from([self.radius, self.y_max, self.radius]),
+        )
+    }
+}
+
+pub fn build_revsurface(
+    revsurface_attr: &JsonValue,
+    materials: &[Arc<dyn Material + Send + Sync>],
+) -> Arc<RevSurface> {
+    let material_index = revsurface_attr["MaterialIndex"].as_usize().unwrap();
+    let curve = build_curve(&revsurface_attr["Curve"]);
+    assert_not_emissive(&materials[material_index], "RevSurface");
+    Arc::new(RevSurface::new(materials[material_index].clone(), curve))
+}
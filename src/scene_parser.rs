@@ -2,15 +2,22 @@ use crate::{
     camera::{build_camera, Camera},
     lights::{build_light, Light},
     materials::{build_material, Material},
-    object3d::{build_group, Group},
+    medium::{build_medium, Medium},
+    object3d::{build_group, Group, Object3d},
+    settings::{build_settings, RenderSettings},
 };
 use std::sync::Arc;
 
 pub struct SceneParser {
     pub camera: Arc<dyn Camera + Send + Sync>,
     pub lights: Vec<Arc<dyn Light + Send + Sync>>,
+    #[allow(dead_code)]
     pub materials: Vec<Arc<dyn Material + Send + Sync>>,
     pub group: Arc<Group>,
+    pub light_group: Arc<Group>, //光源的外形，只给相机光线求交
+    pub medium: Option<Medium>,  //充满整个场景的介质
+    pub bounded_media: bool,     //有没有物体里面带介质，有的话相机和光源可能在物体里面
+    pub settings: RenderSettings,
 }
 pub fn build_scene_parser(scene_name: String) -> SceneParser {
     let json_raw = std::fs::read_to_string(scene_name).expect("File not exist!");
@@ -24,15 +31,29 @@ pub fn build_scene_parser(scene_name: String) -> SceneParser {
     assert!(materials.is_array());
     assert!(group.is_array());
     let camera = build_camera(camera);
-    let lights: Vec<Arc<dyn Light + Send + Sync>> =
-        lights.members().map(|x| build_light(x)).collect();
+    let mut lights: Vec<Arc<dyn Light + Send + Sync>> = lights.members().map(build_light).collect();
     let materials: Vec<Arc<dyn Material + Send + Sync>> =
-        materials.members().map(|x| build_material(x)).collect();
+        materials.members().map(build_material).collect();
     let group: Arc<Group> = build_group(group, &materials);
+    // 发光的物体也是光源
+    for light in group.area_lights() {
+        lights.push(Arc::new(light));
+    }
+    let mut light_group = Group::new();
+    for shape in lights.iter().filter_map(|light| light.shape()) {
+        light_group.add_object(shape);
+    }
+    light_group.build_bvh();
+    let settings = build_settings(&json_parsed["Settings"]);
+    let bounded_media = materials.iter().any(|material| material.medium().is_some());
     SceneParser {
         camera,
         lights,
         materials,
         group,
+        light_group: Arc::new(light_group),
+        medium: build_medium(&json_parsed["Medium"]),
+        bounded_media,
+        settings,
     }
 }
@@ -0,0 +1,259 @@
+use crate::{
+    kernel::Kernel,
+    photon::PhotonMapType,
+    tonemap::{ToneMapper, ToneMapping},
+};
+use json::{object, JsonValue};
+
+// 渲染参数，先取默认值，再用场景文件里的Settings和命令行参数依次覆盖
+#[derive(Clone, Copy)]
+pub struct RenderSettings {
+    pub photon_number: usize, //每轮每个光源发出的光子数
+    pub round_number: usize,
+    pub sample_number: usize, //每个像素每轮的采样数
+    pub parallel_number: usize,
+    pub initial_radius: f64,
+    pub alpha: f64,
+    pub t_min: f64, //求交时的最小距离，防止自相交
+    pub max_depth: usize,
+    pub checkpoint_interval: usize, //每隔几轮写一次检查点
+    pub progress_interval: usize,   //每隔几轮输出一次中间结果，0表示不输出
+    pub progress_seconds: f64,      //每隔多少秒输出一次中间结果，0表示不输出
+    pub progress_numbered: bool,    //中间结果是否按轮数编号，分别存成不同的文件
+    pub tone_mapper: ToneMapper,    //只用于8位图片的输出
+    pub spectral: bool,             //光谱模式，像素里累加的是XYZ
+    pub volume_radius: f64,         //体积光子的初始查询半径
+    pub photon_map: PhotonMapType,  //光子图的加速结构
+    pub visible_points: bool,       //先记下可见点，光子直接累加上去，不存光子图
+    pub kernel: Kernel,             //收集光子时按距离加权的核函数
+}
+
+impl RenderSettings {
+    pub fn new() -> Self {
+        Self {
+            photon_number: 1000000,
+            round_number: 5,
+            sample_number: 8,
+            parallel_number: 8,
+            initial_radius: 0.5,
+            alpha: 0.7,
+            t_min: 0.015,
+            max_depth: 100,
+            checkpoint_interval: 1,
+            progress_interval: 0,
+            progress_seconds: 0.,
+            progress_numbered: false,
+            tone_mapper: ToneMapper::new(),
+            spectral: false,
+            volume_radius: 1.0,
+            photon_map: PhotonMapType::KDTree,
+            visible_points: false,
+            kernel: Kernel::Box,
+        }
+    }
+    // 场景文件里没写的项保持原值
+    pub fn update(&mut self, settings_attr: &JsonValue) {
+        if let Some(photon_number) = settings_attr["Photons"].as_usize() {
+            self.photon_number = photon_number;
+        }
+        if let Some(round_number) = settings_attr["Rounds"].as_usize() {
+            self.round_number = round_number;
+        }
+        if let Some(sample_number) = settings_attr["Samples"].as_usize() {
+            self.sample_number = sample_number;
+        }
+        if let Some(parallel_number) = settings_attr["Threads"].as_usize() {
+            self.parallel_number = parallel_number;
+        }
+        if let Some(initial_radius) = settings_attr["InitialRadius"].as_f64() {
+            self.initial_radius = initial_radius;
+        }
+        if let Some(alpha) = settings_attr["Alpha"].as_f64() {
+            self.alpha = alpha;
+        }
+        if let Some(t_min) = settings_attr["Epsilon"].as_f64() {
+            self.t_min = t_min;
+        }
+        if let Some(max_depth) = settings_attr["MaxDepth"].as_usize() {
+            self.max_depth = max_depth;
+        }
+        if let Some(checkpoint_interval) = settings_attr["CheckpointInterval"].as_usize() {
+            self.checkpoint_interval = checkpoint_interval;
+        }
+        if let Some(progress_interval) = settings_attr["ProgressInterval"].as_usize() {
+            self.progress_interval = progress_interval;
+        }
+        if let Some(progress_seconds) = settings_attr["ProgressSeconds"].as_f64() {
+            self.progress_seconds = progress_seconds;
+        }
+        if let Some(progress_numbered) = settings_attr["ProgressNumbered"].as_bool() {
+            self.progress_numbered = progress_numbered;
+        }
+        if let Some(operator) = settings_attr["ToneMapping"].as_str() {
+            self.tone_mapper.operator = ToneMapping::from_name(operator);
+        }
+        if let Some(exposure) = settings_attr["Exposure"].as_f64() {
+            self.tone_mapper.exposure = exposure;
+        }
+        if let Some(white_point) = settings_attr["WhitePoint"].as_f64() {
+            self.tone_mapper.white_point = Some(white_point);
+        }
+        if let Some(srgb) = settings_attr["SRGB"].as_bool() {
+            self.tone_mapper.srgb = srgb;
+        }
+        if let Some(dither) = settings_attr["Dither"].as_bool() {
+            self.tone_mapper.dither = dither;
+        }
+        if let Some(spectral) = settings_attr["Spectral"].as_bool() {
+            self.spectral = spectral;
+        }
+        if let Some(volume_radius) = settings_attr["VolumeRadius"].as_f64() {
+            self.volume_radius = volume_radius;
+        }
+        if let Some(photon_map) = settings_attr["PhotonMap"].as_str() {
+            self.photon_map = PhotonMapType::from_name(photon_map);
+        }
+        if let Some(visible_points) = settings_attr["VisiblePoints"].as_bool() {
+            self.visible_points = visible_points;
+        }
+        if let Some(kernel) = settings_attr["Kernel"].as_str() {
+            self.kernel = Kernel::from_name(kernel);
+        }
+        self.check();
+    }
+    // 命令行里的选项，覆盖场景文件里的设置
+    pub fn apply_options(&mut self, options: &[(String, String)]) {
+        for (option, value) in options {
+            let parse_usize = |value: &str| -> usize {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for {}: {}", option, value))
+            };
+            let parse_f64 = |value: &str| -> f64 {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for {}: {}", option, value))
+            };
+            let parse_bool = |value: &str| -> bool {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid value for {}: {}", option, value))
+            };
+            match option.as_str() {
+                "--photons" => self.photon_number = parse_usize(value),
+                "--rounds" => self.round_number = parse_usize(value),
+                "--spp" => self.sample_number = parse_usize(value),
+                "--threads" => self.parallel_number = parse_usize(value),
+                "--radius" => self.initial_radius = parse_f64(value),
+                "--alpha" => self.alpha = parse_f64(value),
+                "--epsilon" => self.t_min = parse_f64(value),
+                "--max-depth" => self.max_depth = parse_usize(value),
+                "--checkpoint-interval" => self.checkpoint_interval = parse_usize(value),
+                "--progress-interval" => self.progress_interval = parse_usize(value),
+                "--progress-seconds" => self.progress_seconds = parse_f64(value),
+                "--progress-numbered" => self.progress_numbered = parse_bool(value),
+                "--tonemap" => self.tone_mapper.operator = ToneMapping::from_name(value),
+                "--exposure" => self.tone_mapper.exposure = parse_f64(value),
+                "--white-point" => self.tone_mapper.white_point = Some(parse_f64(value)),
+                "--srgb" => self.tone_mapper.srgb = parse_bool(value),
+                "--dither" => self.tone_mapper.dither = parse_bool(value),
+                "--spectral" => self.spectral = parse_bool(value),
+                "--volume-radius" => self.volume_radius = parse_f64(value),
+                "--photon-map" => self.photon_map = PhotonMapType::from_name(value),
+                "--visible-points" => self.visible_points = parse_bool(value),
+                "--kernel" => self.kernel = Kernel::from_name(value),
+                _ => panic!("Unknown option {}", option),
+            }
+        }
+        self.check();
+    }
+    // 光子数、采样数、线程数、轮数是0时渲染不出东西，甚至会除以0；
+    // Alpha是每轮保留的光子比例，不在(0, 1]里半径会变成0或者越来越大
+    fn check(&self) {
+        for (name, option, value) in [
+            ("Photons", "--photons", self.photon_number),
+            ("Rounds", "--rounds", self.round_number),
+            ("Samples", "--spp", self.sample_number),
+            ("Threads", "--threads", self.parallel_number),
+        ] {
+            assert!(value > 0, "{} ({}) must be at least 1", name, option);
+        }
+        assert!(
+            self.alpha > 0. && self.alpha <= 1.,
+            "Alpha (--alpha) must be in (0, 1]"
+        );
+    }
+    // 和场景文件里Settings的格式一样，检查点里存的就是这个
+    pub fn to_json(self) -> JsonValue {
+        object! {
+            "Photons" => self.photon_number,
+            "Rounds" => self.round_number,
+            "Samples" => self.sample_number,
+            "Threads" => self.parallel_number,
+            "InitialRadius" => self.initial_radius,
+            "Alpha" => self.alpha,
+            "Epsilon" => self.t_min,
+            "MaxDepth" => self.max_depth,
+            "CheckpointInterval" => self.checkpoint_interval,
+            "ProgressInterval" => self.progress_interval,
+            "ProgressSeconds" => self.progress_seconds,
+            "ProgressNumbered" => self.progress_numbered,
+            "ToneMapping" => self.tone_mapper.operator.name(),
+            "Exposure" => self.tone_mapper.exposure,
+            "WhitePoint" => self.tone_mapper.white_point,
+            "SRGB" => self.tone_mapper.srgb,
+            "Dither" => self.tone_mapper.dither,
+            "Spectral" => self.spectral,
+            "VolumeRadius" => self.volume_radius,
+            "PhotonMap" => self.photon_map.name(),
+            "VisiblePoints" => self.visible_points,
+            "Kernel" => self.kernel.name(),
+        }
+    }
+}
+
+pub fn build_settings(settings_attr: &JsonValue) -> RenderSettings {
+    let mut settings = RenderSettings::new();
+    if settings_attr.is_object() {
+        settings.update(settings_attr);
+    }
+    settings
+}
+
+// 开关类的选项，后面没有跟true或false时就是打开
+const FLAGS: [&str; 5] = [
+    "--progress-numbered",
+    "--srgb",
+    "--dither",
+    "--spectral",
+    "--visible-points",
+];
+
+// 把命令行参数分成位置参数和形如--photons 100000的选项
+pub fn parse_args<I>(args: I) -> (Vec<String>, Vec<(String, String)>)
+where
+    I: Iterator<Item = String>,
+{
+    let mut args = args.peekable();
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg.starts_with("--") {
+            let has_value = !FLAGS.contains(&arg.as_str())
+                || matches!(
+                    args.peek().map(String::as_str),
+                    Some("true") | Some("false")
+                );
+            let value = if has_value {
+                args.next()
+                    .unwrap_or_else(|| panic!("No value specified for {}.", arg))
+            } else {
+                String::from("true")
+            };
+            options.push((arg, value));
+        } else {
+            positional.push(arg);
+        }
+    }
+    (positional, options)
+}
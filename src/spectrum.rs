@@ -0,0 +1,143 @@
+use core::f64;
+use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
+use vecmat::vector::Vector3;
+
+// 光谱模式只考虑可见光，波长单位是纳米
+pub const LAMBDA_MIN: f64 = 380.;
+pub const LAMBDA_MAX: f64 = 780.;
+// 相机光线只收集和自己在同一个波段里的光子
+pub const BINS: usize = 16;
+
+// 分段高斯函数，左右两边的宽度不同
+fn gaussian(lambda: f64, mu: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let sigma = if lambda < mu { sigma_left } else { sigma_right };
+    let t = (lambda - mu) / sigma;
+    f64::exp(-0.5 * t * t)
+}
+
+// CIE 1931色匹配函数，用Wyman等人的多峰高斯拟合
+fn cmf(lambda: f64) -> Vector3<f64> {
+    Vector3::<f64>::from([
+        1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2),
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1),
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8),
+    ])
+}
+
+// 在整个波长范围上数值积分
+fn integrate<F>(f: F) -> f64
+where
+    F: Fn(f64) -> f64,
+{
+    let steps = 400;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+    (0..steps)
+        .map(|i| f(LAMBDA_MIN + (i as f64 + 0.5) * step) * step)
+        .sum()
+}
+
+fn xyz_to_linear_srgb(xyz: &Vector3<f64>) -> Vector3<f64> {
+    Vector3::<f64>::from([
+        3.2404542 * xyz.x() - 1.5371385 * xyz.y() - 0.4985314 * xyz.z(),
+        -0.9692660 * xyz.x() + 1.8760108 * xyz.y() + 0.0415560 * xyz.z(),
+        0.0556434 * xyz.x() - 0.2040259 * xyz.y() + 1.0572252 * xyz.z(),
+    ])
+}
+
+lazy_static! {
+    // 常数为1的光谱的Y，用来归一化亮度
+    static ref Y_INTEGRAL: f64 = integrate(|lambda| cmf(lambda).y());
+    // 常数为1的光谱换到sRGB之后的颜色，输出时除掉它，让等能白光还是白色
+    static ref WHITE: Vector3<f64> = xyz_to_linear_srgb(&Vector3::<f64>::from([
+        integrate(|lambda| cmf(lambda).x()),
+        integrate(|lambda| cmf(lambda).y()),
+        integrate(|lambda| cmf(lambda).z()),
+    ])) / *Y_INTEGRAL;
+}
+
+fn sigmoid(x: f64) -> f64 {
+    1. / (1. + f64::exp(-x))
+}
+
+// RGB上采样成光谱：三个平滑的基函数，处处加起来等于1，
+// 所以白色还是常数1，[0, 1]之间的反射率上采样之后也在[0, 1]之间
+pub fn to_spectrum(rgb: &Vector3<f64>, lambda: f64) -> f64 {
+    let red = sigmoid((lambda - 590.) / 12.);
+    let blue = 1. - sigmoid((lambda - 490.) / 12.);
+    let green = 1. - red - blue;
+    rgb.x() * red + rgb.y() * green + rgb.z() * blue
+}
+
+// 光线上的值要乘的颜色：RGB模式原样返回，光谱模式换成光线波长上的值，三个分量都一样
+pub fn tint(wavelength: Option<f64>, color: &Vector3<f64>) -> Vector3<f64> {
+    match wavelength {
+        Some(lambda) => Vector3::<f64>::fill(to_spectrum(color, lambda)),
+        None => *color,
+    }
+}
+
+// 把整个范围分成strata段，在第stratum段里均匀取一个波长，整体还是均匀分布
+pub fn sample_wavelength(stratum: usize, strata: usize) -> f64 {
+    let u = (stratum as f64 + thread_rng().gen_range(0.0..1.0)) / strata as f64;
+    LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * u
+}
+
+pub fn bin(lambda: f64) -> usize {
+    (((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * BINS as f64) as usize).min(BINS - 1)
+}
+
+// 单一波长的值记到像素里：除以均匀采样的概率密度，换成XYZ
+pub fn to_xyz(lambda: f64, value: f64) -> Vector3<f64> {
+    cmf(lambda) * (value * (LAMBDA_MAX - LAMBDA_MIN) / *Y_INTEGRAL)
+}
+
+// 记到像素里的值：RGB模式原样返回，光谱模式按光线的波长换成XYZ
+pub fn to_pixel(wavelength: Option<f64>, value: &Vector3<f64>) -> Vector3<f64> {
+    match wavelength {
+        Some(lambda) => to_xyz(lambda, value.x()),
+        None => *value,
+    }
+}
+
+// 输出时把XYZ换回线性sRGB，白点是等能白光
+pub fn xyz_to_rgb(xyz: &Vector3<f64>) -> Vector3<f64> {
+    xyz_to_linear_srgb(xyz) / *WHITE
+}
+
+// 普朗克定律，只要相对值
+fn planck(lambda: f64, temperature: f64) -> f64 {
+    let lambda = lambda * 1e-9;
+    let c2 = 1.4387769e-2; //hc/k，单位m·K
+    1. / (lambda.powi(5) * (f64::exp(c2 / (lambda * temperature)) - 1.))
+}
+
+// 温度为temperature的黑体辐射，归一化成和常数1的光谱一样亮
+#[derive(Clone, Copy)]
+pub struct Blackbody {
+    temperature: f64,
+    scale: f64,
+}
+
+impl Blackbody {
+    pub fn new(temperature: f64) -> Self {
+        let luminance = integrate(|l| planck(l, temperature) * cmf(l).y()) / *Y_INTEGRAL;
+        Self {
+            temperature,
+            scale: 1. / luminance,
+        }
+    }
+    pub fn value(&self, lambda: f64) -> f64 {
+        planck(lambda, self.temperature) * self.scale
+    }
+    // RGB模式下的颜色，超出色域的分量截到0
+    pub fn rgb(&self) -> Vector3<f64> {
+        let xyz = Vector3::<f64>::from([
+            integrate(|l| self.value(l) * cmf(l).x()),
+            integrate(|l| self.value(l) * cmf(l).y()),
+            integrate(|l| self.value(l) * cmf(l).z()),
+        ]) / *Y_INTEGRAL;
+        xyz_to_rgb(&xyz).map(|x| x.max(0.))
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    hashgrid::{counting_sort, GridHash},
+    kernel::Kernel,
+    medium::{volume_normalization, VolumePoint},
+    photon::{HitPoint, Photon, VisiblePoint},
+    utils::AtomicF64,
+};
+use vecmat::vector::Vector3;
+
+// 可见点和它所在的像素
+pub type PixelPoints<T> = Vec<(usize, T)>;
+
+// 按收集球把点放进哈希网格，给一个位置能找到收集球可能盖住它的点
+struct PointGrid {
+    grid: GridHash,
+    cell_start: Vec<usize>, //第i个表项的点是order[cell_start[i]..cell_start[i + 1]]
+    order: Vec<usize>,
+}
+
+impl PointGrid {
+    // spheres里是每个点的位置和收集半径，格子取最大的半径
+    fn new(spheres: &[(Vector3<f64>, f64)]) -> Self {
+        let max_radius = spheres.iter().map(|sphere| sphere.1).fold(0., f64::max);
+        let grid = GridHash::new(max_radius, spheres.len());
+        let mut hashes = Vec::new();
+        let mut point_hashes = Vec::new();
+        for (i, (pos, radius)) in spheres.iter().enumerate() {
+            let (lo, hi) = grid.cells(pos, *radius);
+            point_hashes.clear();
+            for x in lo[0]..=hi[0] {
+                for y in lo[1]..=hi[1] {
+                    for z in lo[2]..=hi[2] {
+                        point_hashes.push(grid.hash(&[x, y, z]));
+                    }
+                }
+            }
+            // 一个点盖住的几个格子可能哈希到同一个表项，只放一次
+            point_hashes.sort_unstable();
+            point_hashes.dedup();
+            hashes.extend(point_hashes.iter().map(|&hash| (hash, i)));
+        }
+        let (cell_start, order) = counting_sort(&hashes, grid.table_size());
+        Self {
+            grid,
+            cell_start,
+            order,
+        }
+    }
+    fn for_each_near<F>(&self, pos: &Vector3<f64>, mut f: F)
+    where
+        F: FnMut(usize),
+    {
+        let hash = self.grid.hash(&self.grid.cell(pos));
+        for &i in &self.order[self.cell_start[hash]..self.cell_start[hash + 1]] {
+            f(i);
+        }
+    }
+}
+
+// 一个像素这一轮收到的光子，多个线程同时往里加
+struct Accumulator {
+    n: AtomicF64,
+    tau: [AtomicF64; 3],
+}
+
+impl Accumulator {
+    fn new() -> Self {
+        Self {
+            n: AtomicF64::new(0.),
+            tau: [AtomicF64::new(0.), AtomicF64::new(0.), AtomicF64::new(0.)],
+        }
+    }
+    fn add(&self, value: &Vector3<f64>) {
+        self.n.add(1.);
+        for (i, tau) in self.tau.iter().enumerate() {
+            tau.add(value[i]);
+        }
+    }
+    fn tau(&self) -> Vector3<f64> {
+        Vector3::<f64>::from([self.tau[0].load(), self.tau[1].load(), self.tau[2].load()])
+    }
+}
+
+// 一轮里所有像素的可见点，光子发出来就直接累加到可见点所在的像素上，不用存下来
+pub struct VisiblePoints {
+    surface: Vec<(usize, VisiblePoint, f64)>, //所在的像素、可见点、收集半径
+    surface_grid: PointGrid,
+    volume: PixelPoints<VolumePoint>,
+    volume_grid: PointGrid,
+    volume_radius: f64,
+    volume_normalization: f64,
+    kernel: Kernel,
+    pixels: Vec<Accumulator>,
+    volume_pixels: Vec<Accumulator>,
+}
+
+impl VisiblePoints {
+    // 表面可见点的收集半径取所在像素当前的半径
+    pub fn new(
+        surface: PixelPoints<VisiblePoint>,
+        volume: PixelPoints<VolumePoint>,
+        picture: &[HitPoint],
+        volume_radius: f64,
+        kernel: Kernel,
+        photon_number: usize,
+    ) -> Self {
+        let surface: Vec<(usize, VisiblePoint, f64)> = surface
+            .into_iter()
+            .map(|(pixel, point)| (pixel, point, picture[pixel].radius))
+            .collect();
+        let surface_spheres: Vec<(Vector3<f64>, f64)> = surface
+            .iter()
+            .map(|(_, point, radius)| (point.pos, *radius))
+            .collect();
+        let volume_spheres: Vec<(Vector3<f64>, f64)> = volume
+            .iter()
+            .map(|(_, point)| (point.pos, volume_radius))
+            .collect();
+        Self {
+            surface_grid: PointGrid::new(&surface_spheres),
+            surface,
+            volume_grid: PointGrid::new(&volume_spheres),
+            volume,
+            volume_radius,
+            volume_normalization: volume_normalization(volume_radius, &kernel, photon_number),
+            kernel,
+            pixels: picture.iter().map(|_| Accumulator::new()).collect(),
+            volume_pixels: picture.iter().map(|_| Accumulator::new()).collect(),
+        }
+    }
+    // 表面光子
+    pub fn deposit(&self, photon: &Photon) {
+        self.surface_grid.for_each_near(&photon.pos(), |i| {
+            let (pixel, point, radius) = &self.surface[i];
+            if let Some(value) = point.contribution(*radius, &self.kernel, photon) {
+                self.pixels[*pixel].add(&value);
+            }
+        });
+    }
+    // 体积光子
+    pub fn deposit_volume(&self, photon: &Photon) {
+        self.volume_grid.for_each_near(&photon.pos(), |i| {
+            let (pixel, point) = &self.volume[i];
+            if let Some(value) = point.contribution(self.volume_radius, &self.kernel, photon) {
+                self.volume_pixels[*pixel].add(&value);
+            }
+        });
+    }
+    // 第pixel个像素这一轮收到的光子，介质里散射过来的辐射亮度记在direct里
+    pub fn gathered(&self, pixel: usize) -> HitPoint {
+        let mut buffer = HitPoint::new(0.);
+        buffer.n = self.pixels[pixel].n.load();
+        buffer.tau = self.pixels[pixel].tau();
+        buffer.direct = self.volume_pixels[pixel].tau() / self.volume_normalization;
+        buffer
+    }
+}
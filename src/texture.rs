@@ -0,0 +1,391 @@
+use crate::utils::parse_vector;
+use json::JsonValue;
+use std::sync::Arc;
+use vecmat::vector::{Vector2, Vector3};
+
+pub trait Texture {
+    // texcoord是物体给出的(u, v)，pos是交点的世界坐标
+    fn value(&self, texcoord: &Vector2<f64>, pos: &Vector3<f64>) -> Vector3<f64>;
+}
+
+pub struct ConstantTexture {
+    color: Vector3<f64>,
+}
+
+impl ConstantTexture {
+    pub fn new(color: Vector3<f64>) -> Self {
+        Self { color }
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _texcoord: &Vector2<f64>, _pos: &Vector3<f64>) -> Vector3<f64> {
+        self.color
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    // 把任意整数下标映射回[0, size)
+    fn apply(&self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        match self {
+            WrapMode::Repeat => index.rem_euclid(size) as usize,
+            WrapMode::Clamp => index.clamp(0, size - 1) as usize,
+            WrapMode::Mirror => {
+                let period = index.rem_euclid(2 * size);
+                if period < size {
+                    period as usize
+                } else {
+                    (2 * size - 1 - period) as usize
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FilterMode {
+    Nearest,
+    Bilinear,
+}
+
+pub struct ImageTexture {
+    pixels: Vec<Vector3<f64>>,
+    width: usize,
+    height: usize,
+    wrap: WrapMode,
+    filter: FilterMode,
+    scale: Vector2<f64>,
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        f64::powf((value + 0.055) / 1.055, 2.4)
+    }
+}
+
+impl ImageTexture {
+    pub fn new(
+        file_name: &str,
+        wrap: WrapMode,
+        filter: FilterMode,
+        scale: Option<Vector2<f64>>,
+        srgb: bool,
+    ) -> Self {
+        let image = image::open(file_name)
+            .expect("Problem loading texture")
+            .to_rgb8();
+        let (width, height) = image.dimensions();
+        // 图片一般是sRGB编码的，渲染器内部全部用线性值
+        let decode = |x: u8| {
+            let x = x as f64 / 255.;
+            if srgb {
+                srgb_to_linear(x)
+            } else {
+                x
+            }
+        };
+        let pixels = image
+            .pixels()
+            .map(|p| Vector3::<f64>::from([decode(p[0]), decode(p[1]), decode(p[2])]))
+            .collect();
+        Self {
+            pixels,
+            width: width as usize,
+            height: height as usize,
+            wrap,
+            filter,
+            scale: scale.unwrap_or_else(|| Vector2::<f64>::from([1., 1.])),
+        }
+    }
+    fn texel(&self, x: i64, y: i64) -> Vector3<f64> {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, texcoord: &Vector2<f64>, _pos: &Vector3<f64>) -> Vector3<f64> {
+        // v轴朝上，图片的第0行在最上面
+        let x = texcoord.x() * self.scale.x() * self.width as f64;
+        let y = (1. - texcoord.y() * self.scale.y()) * self.height as f64;
+        match self.filter {
+            FilterMode::Nearest => self.texel(x.floor() as i64, y.floor() as i64),
+            FilterMode::Bilinear => {
+                // 像素中心在半整数处
+                let x = x - 0.5;
+                let y = y - 0.5;
+                let x0 = x.floor();
+                let y0 = y.floor();
+                let fx = x - x0;
+                let fy = y - y0;
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = self.texel(x0, y0) * (1. - fx) + self.texel(x0 + 1, y0) * fx;
+                let bottom = self.texel(x0, y0 + 1) * (1. - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+                top * (1. - fy) + bottom * fy
+            }
+        }
+    }
+}
+
+pub struct CheckerTexture {
+    colors: [Vector3<f64>; 2],
+    scale: f64,
+}
+
+impl CheckerTexture {
+    pub fn new(colors: [Vector3<f64>; 2], scale: Option<f64>) -> Self {
+        Self {
+            colors,
+            scale: scale.unwrap_or(1.),
+        }
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, _texcoord: &Vector2<f64>, pos: &Vector3<f64>) -> Vector3<f64> {
+        // 三维棋盘格，边长为1/scale的立方体交替着色
+        let p = *pos * self.scale;
+        let parity = (p.x().floor() + p.y().floor() + p.z().floor()) as i64;
+        self.colors[parity.rem_euclid(2) as usize]
+    }
+}
+
+// Ken Perlin的improved noise，排列表由种子打乱得到
+pub struct Perlin {
+    perm: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut perm: Vec<usize> = (0..256).collect();
+        let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        for i in (1..256).rev() {
+            // xorshift64，只用来洗牌，保证同一个种子每次结果一样
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            perm.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+        let perm = perm.iter().chain(perm.iter()).copied().collect();
+        Self { perm }
+    }
+    fn fade(t: f64) -> f64 {
+        t * t * t * (t * (t * 6. - 15.) + 10.)
+    }
+    fn lerp(t: f64, a: f64, b: f64) -> f64 {
+        a + t * (b - a)
+    }
+    fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 {
+            y
+        } else if h == 12 || h == 14 {
+            x
+        } else {
+            z
+        };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+    // 返回值大致在[-1, 1]之间
+    pub fn noise(&self, pos: &Vector3<f64>) -> f64 {
+        let (fx, fy, fz) = (pos.x().floor(), pos.y().floor(), pos.z().floor());
+        let xi = (fx as i64).rem_euclid(256) as usize;
+        let yi = (fy as i64).rem_euclid(256) as usize;
+        let zi = (fz as i64).rem_euclid(256) as usize;
+        let (x, y, z) = (pos.x() - fx, pos.y() - fy, pos.z() - fz);
+        let (u, v, w) = (Self::fade(x), Self::fade(y), Self::fade(z));
+        let p = &self.perm;
+        let a = p[xi] + yi;
+        let aa = p[a] + zi;
+        let ab = p[a + 1] + zi;
+        let b = p[xi + 1] + yi;
+        let ba = p[b] + zi;
+        let bb = p[b + 1] + zi;
+        Self::lerp(
+            w,
+            Self::lerp(
+                v,
+                Self::lerp(
+                    u,
+                    Self::grad(p[aa], x, y, z),
+                    Self::grad(p[ba], x - 1., y, z),
+                ),
+                Self::lerp(
+                    u,
+                    Self::grad(p[ab], x, y - 1., z),
+                    Self::grad(p[bb], x - 1., y - 1., z),
+                ),
+            ),
+            Self::lerp(
+                v,
+                Self::lerp(
+                    u,
+                    Self::grad(p[aa + 1], x, y, z - 1.),
+                    Self::grad(p[ba + 1], x - 1., y, z - 1.),
+                ),
+                Self::lerp(
+                    u,
+                    Self::grad(p[ab + 1], x, y - 1., z - 1.),
+                    Self::grad(p[bb + 1], x - 1., y - 1., z - 1.),
+                ),
+            ),
+        )
+    }
+    // 分形布朗运动，每一层频率翻倍、振幅减半
+    pub fn fbm(&self, pos: &Vector3<f64>, octaves: usize) -> f64 {
+        let mut sum = 0.;
+        let mut amplitude = 1.;
+        let mut p = *pos;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(&p);
+            amplitude *= 0.5;
+            p *= 2.;
+        }
+        sum
+    }
+    // 和fbm一样，只是每一层取绝对值
+    pub fn turbulence(&self, pos: &Vector3<f64>, octaves: usize) -> f64 {
+        let mut sum = 0.;
+        let mut amplitude = 1.;
+        let mut p = *pos;
+        for _ in 0..octaves {
+            sum += amplitude * self.noise(&p).abs();
+            amplitude *= 0.5;
+            p *= 2.;
+        }
+        sum
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum NoisePattern {
+    Fbm,
+    Marble,
+    Wood,
+}
+
+// 噪声、大理石、木纹都是在两个颜色之间按噪声插值，只是插值系数的算法不同
+pub struct NoiseTexture {
+    perlin: Perlin,
+    pattern: NoisePattern,
+    colors: [Vector3<f64>; 2],
+    scale: f64,
+    turbulence: f64,
+    octaves: usize,
+}
+
+impl NoiseTexture {
+    pub fn new(
+        pattern: NoisePattern,
+        colors: [Vector3<f64>; 2],
+        scale: Option<f64>,
+        turbulence: Option<f64>,
+        octaves: Option<usize>,
+        seed: Option<u64>,
+    ) -> Self {
+        Self {
+            perlin: Perlin::new(seed.unwrap_or(0)),
+            pattern,
+            colors,
+            scale: scale.unwrap_or(1.),
+            turbulence: turbulence.unwrap_or(5.),
+            octaves: octaves.unwrap_or(6),
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _texcoord: &Vector2<f64>, pos: &Vector3<f64>) -> Vector3<f64> {
+        let p = *pos * self.scale;
+        let t = match self.pattern {
+            NoisePattern::Fbm => 0.5 * (1. + self.perlin.fbm(&p, self.octaves)),
+            NoisePattern::Marble => {
+                // 沿x方向的正弦条纹被湍流扰动
+                let phase = p.x() + self.turbulence * self.perlin.turbulence(&p, self.octaves);
+                0.5 * (1. + f64::sin(phase))
+            }
+            NoisePattern::Wood => {
+                // 绕y轴的同心年轮，半径被噪声扰动
+                let r = f64::sqrt(p.x() * p.x() + p.z() * p.z())
+                    + self.turbulence * 0.1 * self.perlin.fbm(&p, self.octaves);
+                // 扰动后r可能是负的，用rem_euclid保证年轮的值在[0, 1)里
+                let ring = (r * 4.).rem_euclid(1.);
+                ring * ring
+            }
+        };
+        let t = t.clamp(0., 1.);
+        self.colors[0] * (1. - t) + self.colors[1] * t
+    }
+}
+
+fn parse_colors(raw: &JsonValue) -> [Vector3<f64>; 2] {
+    [parse_vector(&raw[0]), parse_vector(&raw[1])]
+}
+
+// 颜色贴图默认按sRGB解码，法线贴图和高度图存的是数据，默认不解码
+pub fn build_texture(
+    texture_attr: &JsonValue,
+    srgb_default: bool,
+) -> Arc<dyn Texture + Send + Sync> {
+    let texture_type = texture_attr["Type"].as_str().unwrap();
+    match texture_type {
+        "Constant" => Arc::new(ConstantTexture::new(parse_vector(&texture_attr["Color"]))),
+        "Image" => {
+            let file_name = texture_attr["File"].as_str().unwrap();
+            let wrap = match texture_attr["Wrap"].as_str().unwrap_or("Repeat") {
+                "Repeat" => WrapMode::Repeat,
+                "Clamp" => WrapMode::Clamp,
+                "Mirror" => WrapMode::Mirror,
+                _ => panic!("Wrong wrap mode!"),
+            };
+            let filter = match texture_attr["Filter"].as_str().unwrap_or("Bilinear") {
+                "Nearest" => FilterMode::Nearest,
+                "Bilinear" => FilterMode::Bilinear,
+                _ => panic!("Wrong filter mode!"),
+            };
+            let scale = if texture_attr["Scale"].is_array() {
+                let scale = &texture_attr["Scale"];
+                Some(Vector2::<f64>::from([
+                    scale[0].as_f64().unwrap(),
+                    scale[1].as_f64().unwrap(),
+                ]))
+            } else {
+                None
+            };
+            let srgb = texture_attr["SRGB"].as_bool().unwrap_or(srgb_default);
+            Arc::new(ImageTexture::new(file_name, wrap, filter, scale, srgb))
+        }
+        "Checker" => Arc::new(CheckerTexture::new(
+            parse_colors(&texture_attr["Colors"]),
+            texture_attr["Scale"].as_f64(),
+        )),
+        "Noise" | "Marble" | "Wood" => {
+            let pattern = match texture_type {
+                "Noise" => NoisePattern::Fbm,
+                "Marble" => NoisePattern::Marble,
+                _ => NoisePattern::Wood,
+            };
+            Arc::new(NoiseTexture::new(
+                pattern,
+                parse_colors(&texture_attr["Colors"]),
+                texture_attr["Scale"].as_f64(),
+                texture_attr["Turbulence"].as_f64(),
+                texture_attr["Octaves"].as_usize(),
+                texture_attr["Seed"].as_u64(),
+            ))
+        }
+        _ => panic!("Wrong texture type!"),
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const TILE_SIZE: usize = 32;
+
+// 图像上[x_begin, x_end) x [y_begin, y_end)这一块
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub x_begin: usize,
+    pub x_end: usize,
+    pub y_begin: usize,
+    pub y_end: usize,
+}
+
+impl Tile {
+    pub fn pixels(&self) -> impl Iterator<Item = (usize, usize)> {
+        let (x_begin, x_end) = (self.x_begin, self.x_end);
+        (self.y_begin..self.y_end).flat_map(move |y| (x_begin..x_end).map(move |x| (x, y)))
+    }
+}
+
+// 线程每次从队列里取一块去渲染，先做完的线程接着取，负载自然就均衡了
+pub struct TileQueue {
+    tiles: Vec<Tile>,
+    next: AtomicUsize,
+}
+
+impl TileQueue {
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut tiles = Vec::new();
+        for y_begin in (0..height).step_by(TILE_SIZE) {
+            for x_begin in (0..width).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x_begin,
+                    x_end: (x_begin + TILE_SIZE).min(width),
+                    y_begin,
+                    y_end: (y_begin + TILE_SIZE).min(height),
+                });
+            }
+        }
+        Self {
+            tiles,
+            next: AtomicUsize::new(0),
+        }
+    }
+    pub fn next(&self) -> Option<Tile> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tiles.get(index).copied()
+    }
+}
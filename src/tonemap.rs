@@ -0,0 +1,124 @@
+use rand::{thread_rng, Rng};
+use vecmat::{traits::Dot, vector::Vector3};
+
+#[derive(Clone, Copy)]
+pub enum ToneMapping {
+    Clamp, //算子本身不变，超出[0, 1]的直接截断；sRGB编码另外由srgb控制
+    Reinhard,
+    ReinhardExtended,
+    Aces,
+    Hable,
+}
+
+impl ToneMapping {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "Clamp" => Self::Clamp,
+            "Reinhard" => Self::Reinhard,
+            "ReinhardExtended" => Self::ReinhardExtended,
+            "ACES" => Self::Aces,
+            "Hable" => Self::Hable,
+            _ => panic!("Unknown tone mapping {}", name),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Clamp => "Clamp",
+            Self::Reinhard => "Reinhard",
+            Self::ReinhardExtended => "ReinhardExtended",
+            Self::Aces => "ACES",
+            Self::Hable => "Hable",
+        }
+    }
+}
+
+// 线性辐射亮度 -> 曝光 -> 色调映射 -> sRGB编码 -> 抖动 -> 8位
+#[derive(Clone, Copy)]
+pub struct ToneMapper {
+    pub operator: ToneMapping,
+    pub exposure: f64,            //曝光，单位是档，乘上2^exposure
+    pub white_point: Option<f64>, //映射成纯白的亮度，没给就用各个算子的默认值
+    pub srgb: bool,
+    pub dither: bool,
+}
+
+fn luminance(color: &Vector3<f64>) -> f64 {
+    color.dot(Vector3::<f64>::from([0.2126, 0.7152, 0.0722]))
+}
+
+// 按亮度缩放，保持色相
+fn scale_luminance<F>(color: Vector3<f64>, f: F) -> Vector3<f64>
+where
+    F: Fn(f64) -> f64,
+{
+    let l = luminance(&color);
+    if l <= 0. {
+        Vector3::<f64>::fill(0.)
+    } else {
+        color * (f(l) / l)
+    }
+}
+
+// Narkowicz拟合的ACES曲线
+fn aces(x: f64) -> f64 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+// Uncharted 2里Hable的曲线
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+fn srgb_oetf(x: f64) -> f64 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1. / 2.4) - 0.055
+    }
+}
+
+impl ToneMapper {
+    pub fn new() -> Self {
+        Self {
+            operator: ToneMapping::Clamp,
+            exposure: 0.,
+            white_point: None,
+            srgb: true,
+            dither: false,
+        }
+    }
+    // 映射到[0, 1]的线性显示值
+    pub fn map(&self, radiance: &Vector3<f64>) -> Vector3<f64> {
+        let color = *radiance * 2_f64.powf(self.exposure);
+        let color = match self.operator {
+            ToneMapping::Clamp => color,
+            ToneMapping::Reinhard => scale_luminance(color, |l| l / (1. + l)),
+            ToneMapping::ReinhardExtended => {
+                let white = self.white_point.unwrap_or(4.);
+                scale_luminance(color, |l| l * (1. + l / (white * white)) / (1. + l))
+            }
+            ToneMapping::Aces => color.map(aces),
+            ToneMapping::Hable => {
+                let white = self.white_point.unwrap_or(11.2);
+                color.map(|x| hable(x) / hable(white))
+            }
+        };
+        color.map(|x| x.clamp(0., 1.))
+    }
+    pub fn encode(&self, radiance: &Vector3<f64>) -> [u8; 3] {
+        let color = self.map(radiance);
+        let mut rng = thread_rng();
+        let mut ret = [0_u8; 3];
+        for (i, value) in color.iter().enumerate() {
+            let mut value = if self.srgb { srgb_oetf(*value) } else { *value };
+            // 三角分布的噪声，消掉暗部平滑渐变里的色带
+            if self.dither {
+                value += (rng.gen::<f64>() - rng.gen::<f64>()) / 255.;
+            }
+            ret[i] = (value * 255. + 0.5).clamp(0., 255.) as u8;
+        }
+        ret
+    }
+}
@@ -1,9 +1,10 @@
 use crate::hit::Hit;
 use core::f64;
 use json::JsonValue;
-use std::ops::Div;
+use std::sync::atomic::{AtomicU64, Ordering};
 use vecmat::matrix::Matrix4x4;
-use vecmat::vector::Vector3;
+use vecmat::traits::Dot;
+use vecmat::vector::{Vector2, Vector3, Vector4};
 use vecmat::Vector;
 pub fn gen_translation(translation: &Vector3<f64>) -> Matrix4x4<f64> {
     Matrix4x4::from_array_of_arrays([
@@ -14,6 +15,16 @@ pub fn gen_translation(translation: &Vector3<f64>) -> Matrix4x4<f64> {
     ])
 }
 
+pub fn transform_point(mat: &Matrix4x4<f64>, point: &Vector3<f64>) -> Vector3<f64> {
+    let point = mat.dot(Vector4::<f64>::from([point.x(), point.y(), point.z(), 1.]));
+    Vector3::<f64>::from([point.x(), point.y(), point.z()])
+}
+
+pub fn transform_direction(mat: &Matrix4x4<f64>, dir: &Vector3<f64>) -> Vector3<f64> {
+    let dir = mat.dot(Vector4::<f64>::from([dir.x(), dir.y(), dir.z(), 0.]));
+    Vector3::<f64>::from([dir.x(), dir.y(), dir.z()])
+}
+
 pub fn to_radian(degree: f64) -> f64 {
     degree * f64::consts::PI / 180.
 }
@@ -81,26 +92,8 @@ where
     Vector::<T, N>::try_from_iter(a.zip(*b).iter().map(|(x, y)| x.get_max(*y))).unwrap()
 }
 
-pub fn elementwise_division<T, const N: usize>(a: &Vector<T, N>, b: &Vector<T, N>) -> Vector<T, N>
-where
-    T: Copy + Clone + Div<Output = T>,
-{
-    Vector::<T, N>::try_from_iter(a.zip(*b).iter().map(|(x, y)| x.div(*y))).unwrap()
-}
-
-pub fn get_dist(l: f64, r: f64, v: f64) -> f64 {
-    assert!(l <= r);
-    if v < l {
-        l - v
-    } else if l <= v && v <= r {
-        0.
-    } else {
-        v - r
-    }
-}
-
 pub fn gen_vert(vec: &Vector3<f64>) -> Vector3<f64> {
-    let temp = if vec.x() > 0.2 {
+    let temp = if vec.x().abs() > 0.2 {
         Vector3::<f64>::from([0., 1., 0.])
     } else {
         Vector3::<f64>::from([1., 0., 0.])
@@ -115,8 +108,8 @@ pub fn parse_vector(raw: &JsonValue) -> Vector3<f64> {
     ])
 }
 
-pub fn trunc(color: f64) -> u8 {
-    (color * 255.) as u8
+pub fn parse_vector2(raw: &JsonValue) -> Vector2<f64> {
+    Vector2::<f64>::from([raw[0].as_f64().unwrap(), raw[1].as_f64().unwrap()])
 }
 
 pub fn prior_hit(a: Option<Hit>, b: Option<Hit>) -> Option<Hit> {
@@ -134,3 +127,28 @@ pub fn prior_hit(a: Option<Hit>, b: Option<Hit>) -> Option<Hit> {
         a
     }
 }
+
+// 可以被多个线程同时累加的f64，按位存在AtomicU64里
+pub struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    pub fn new(value: f64) -> Self {
+        Self(AtomicU64::new(value.to_bits()))
+    }
+    pub fn add(&self, value: f64) {
+        let mut current = self.0.load(Ordering::Relaxed);
+        loop {
+            let next = (f64::from_bits(current) + value).to_bits();
+            match self
+                .0
+                .compare_exchange_weak(current, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}